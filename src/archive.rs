@@ -0,0 +1,104 @@
+use std::fs::{read_dir, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+/// The files of a chat export, either still in the zip it was downloaded as
+/// or already unpacked into a directory. Files are addressed by index, and
+/// named by their `/`-separated path from the root of the export
+pub enum Archive {
+    Zip {
+        zip: zip::ZipArchive<File>,
+        // `ZipArchive::file_names` isn't in index order
+        names: Vec<String>,
+    },
+    Directory {
+        root: PathBuf,
+        names: Vec<String>,
+    },
+}
+
+fn list_directory(root: &Path, dir: &Path, names: &mut Vec<String>) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_directory(root, &path, names)?;
+        } else {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let components: Vec<_> = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect();
+            names.push(components.join("/"));
+        }
+    }
+
+    Ok(())
+}
+
+impl Archive {
+    /// Opens a zip file, or a directory holding an unpacked export
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Archive> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            let mut names = Vec::new();
+            list_directory(path, path, &mut names)?;
+            names.sort();
+
+            Ok(Archive::Directory {
+                root: path.to_path_buf(),
+                names,
+            })
+        } else {
+            let mut zip = zip::ZipArchive::new(File::open(path)?)?;
+            let names = (0..zip.len())
+                .map(|i| Ok(String::from(zip.by_index_raw(i)?.name())))
+                .collect::<Result<Vec<String>>>()?;
+            Ok(Archive::Zip { zip, names })
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.file_names().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        match self {
+            Archive::Zip { .. } => false,
+            Archive::Directory { .. } => true,
+        }
+    }
+
+    /// Paths of every file, in index order
+    pub fn file_names(&self) -> &[String] {
+        match self {
+            Archive::Zip { names, .. } | Archive::Directory { names, .. } => names,
+        }
+    }
+
+    /// Uncompressed size of the file, in bytes
+    pub fn size(&mut self, idx: usize) -> Result<u64> {
+        match self {
+            Archive::Zip { zip, .. } => Ok(zip.by_index(idx)?.size()),
+            Archive::Directory { root, names } => Ok(root.join(&names[idx]).metadata()?.len()),
+        }
+    }
+
+    pub fn by_index(&mut self, idx: usize) -> Result<Box<dyn Read + '_>> {
+        match self {
+            Archive::Zip { zip, .. } => Ok(Box::new(zip.by_index(idx)?)),
+            Archive::Directory { root, names } => Ok(Box::new(File::open(root.join(&names[idx]))?)),
+        }
+    }
+
+    pub fn read_to_string(&mut self, idx: usize) -> Result<String> {
+        let mut data = String::new();
+        self.by_index(idx)?.read_to_string(&mut data)?;
+        Ok(data)
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use crate::{Conversation, MessageKind, Participant, ReplyTo, SystemEvent};
+
+// What exports call people whose accounts no longer exist
+const UNKNOWN_AUTHORS: &[&str] = &[
+    "Facebook user",
+    "Facebook User",
+    "Instagram User",
+    "Deleted Account",
+];
+
+/// Returns true for the placeholder names of deleted accounts, and for
+/// authors with no name at all
+pub fn is_unknown_author(name: &str) -> bool {
+    name.trim().is_empty() || UNKNOWN_AUTHORS.contains(&name)
+}
+
+/// What to do with messages by unknown authors
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum AuthorPolicy {
+    /// Leave them as the export wrote them
+    #[default]
+    Keep,
+    /// Call every unknown author this instead
+    Map(String),
+    /// Leave out their messages and reactions
+    Drop,
+    /// Name them `User 1`, `User 2`... in the order they first appear
+    Pseudonymize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuthorOptions {
+    pub unknown: AuthorPolicy,
+    /// Leave out messages that were unsent
+    pub drop_unsent: bool,
+}
+
+struct Renamer<'a> {
+    policy: &'a AuthorPolicy,
+    // Names that pseudonyms mustn't clash with
+    known: Vec<String>,
+    pseudonyms: HashMap<String, String>,
+}
+
+impl Renamer<'_> {
+    /// Returns the name to use instead, or `None` to drop them
+    fn rename(&mut self, name: &str) -> Option<String> {
+        if !is_unknown_author(name) {
+            return Some(String::from(name));
+        }
+
+        match self.policy {
+            AuthorPolicy::Keep => Some(String::from(name)),
+            AuthorPolicy::Map(mapped) => Some(mapped.clone()),
+            AuthorPolicy::Drop => None,
+            AuthorPolicy::Pseudonymize => {
+                if let Some(pseudonym) = self.pseudonyms.get(name) {
+                    return Some(pseudonym.clone());
+                }
+                let mut n = self.pseudonyms.len() + 1;
+                let pseudonym = loop {
+                    let pseudonym = format!("User {}", n);
+                    if !self.known.contains(&pseudonym) {
+                        break pseudonym;
+                    }
+                    n += 1;
+                };
+                self.known.push(pseudonym.clone());
+                self.pseudonyms
+                    .insert(String::from(name), pseudonym.clone());
+                Some(pseudonym)
+            }
+        }
+    }
+
+    fn rename_all(&mut self, names: &[String]) -> Vec<String> {
+        names.iter().filter_map(|name| self.rename(name)).collect()
+    }
+}
+
+/// Renames or drops deleted accounts and nameless authors everywhere they
+/// appear in the conversation -- as authors, participants, reactions and
+/// in people joining or leaving -- so that they're consistent throughout
+pub fn normalize_authors(conversation: &mut Conversation, options: &AuthorOptions) {
+    let mut renamer = Renamer {
+        policy: &options.unknown,
+        known: conversation
+            .participants
+            .iter()
+            .map(|p| p.name.clone())
+            .chain(conversation.messages.iter().map(|m| m.author.clone()))
+            .filter(|name| !is_unknown_author(name))
+            .collect(),
+        pseudonyms: HashMap::new(),
+    };
+
+    let messages = std::mem::take(&mut conversation.messages);
+    for mut message in messages {
+        if options.drop_unsent && message.kind == MessageKind::System(SystemEvent::Unsent) {
+            continue;
+        }
+        message.author = match renamer.rename(&message.author) {
+            Some(author) => author,
+            None => continue,
+        };
+
+        let reactions = std::mem::take(&mut message.reactions);
+        for mut reaction in reactions {
+            if let Some(actor) = renamer.rename(&reaction.actor) {
+                reaction.actor = actor;
+                message.reactions.push(reaction);
+            }
+        }
+        if let Some(ReplyTo::Message { author, .. }) = &mut message.reply_to {
+            match renamer.rename(author) {
+                Some(renamed) => *author = renamed,
+                None => message.reply_to = None,
+            }
+        }
+        match &mut message.kind {
+            MessageKind::System(SystemEvent::Joined(names))
+            | MessageKind::System(SystemEvent::Left(names)) => *names = renamer.rename_all(names),
+            _ => {}
+        }
+
+        conversation.messages.push(message);
+    }
+
+    let participants = std::mem::take(&mut conversation.participants);
+    for participant in participants {
+        if let Some(name) = renamer.rename(&participant.name) {
+            let participant = Participant { name };
+            // Several unknown authors may have been mapped to the same name
+            if !conversation.participants.contains(&participant) {
+                conversation.participants.push(participant);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, Reaction};
+    use chrono::{TimeZone, Utc};
+
+    fn conversation() -> Conversation {
+        let timestamp = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let mut hello = Message::text("Facebook user", "hello", timestamp);
+        hello.reactions.push(Reaction {
+            reaction: String::from("👍"),
+            actor: String::from(""),
+        });
+        let mut unsent = Message::text("Alice", "", timestamp);
+        unsent.kind = MessageKind::System(SystemEvent::Unsent);
+
+        Conversation {
+            name: String::from("alice_1"),
+            title: String::from("Alice"),
+            participants: ["Alice", "User 1", "Facebook user", ""]
+                .iter()
+                .map(|name| Participant {
+                    name: String::from(*name),
+                })
+                .collect(),
+            messages: vec![hello, unsent, Message::text("Alice", "hi", timestamp)],
+        }
+    }
+
+    fn participant_names(conversation: &Conversation) -> Vec<&str> {
+        conversation
+            .participants
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_normalize_authors() {
+        let mut pseudonymized = conversation();
+        normalize_authors(
+            &mut pseudonymized,
+            &AuthorOptions {
+                unknown: AuthorPolicy::Pseudonymize,
+                drop_unsent: true,
+            },
+        );
+        assert_eq!(
+            participant_names(&pseudonymized),
+            vec!["Alice", "User 1", "User 2", "User 3"]
+        );
+        assert_eq!(pseudonymized.messages.len(), 2);
+        assert_eq!(pseudonymized.messages[0].author, "User 2");
+        assert_eq!(pseudonymized.messages[0].reactions[0].actor, "User 3");
+
+        let mut mapped = conversation();
+        normalize_authors(
+            &mut mapped,
+            &AuthorOptions {
+                unknown: AuthorPolicy::Map(String::from("Someone")),
+                drop_unsent: false,
+            },
+        );
+        assert_eq!(
+            participant_names(&mapped),
+            vec!["Alice", "User 1", "Someone"]
+        );
+        assert_eq!(mapped.messages.len(), 3);
+
+        let mut dropped = conversation();
+        normalize_authors(
+            &mut dropped,
+            &AuthorOptions {
+                unknown: AuthorPolicy::Drop,
+                drop_unsent: false,
+            },
+        );
+        assert_eq!(participant_names(&dropped), vec!["Alice", "User 1"]);
+        assert_eq!(dropped.messages.len(), 2);
+        assert_eq!(dropped.messages[0].author, "Alice");
+    }
+}
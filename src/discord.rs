@@ -0,0 +1,323 @@
+use chrono::{DateTime, Utc};
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::{
+    get_json_names, resolve_replies, Archive, Attachment, AttachmentKind, ChatSource, Conversation,
+    Error, Message, MessageKind, Participant, ReplyTo, Result, Share,
+};
+
+#[derive(Deserialize)]
+struct RawGuild {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct RawChannel {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct RawAuthor {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawAttachment {
+    url: String,
+    file_name: String,
+}
+
+#[derive(Deserialize)]
+struct RawEmbed {
+    title: Option<String>,
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawSticker {
+    source_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawReference {
+    message_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawMessage {
+    id: String,
+    r#type: String,
+    timestamp: String,
+    content: String,
+    author: RawAuthor,
+    #[serde(default)]
+    attachments: Vec<RawAttachment>,
+    #[serde(default)]
+    embeds: Vec<RawEmbed>,
+    #[serde(default)]
+    stickers: Vec<RawSticker>,
+    reference: Option<RawReference>,
+}
+
+#[derive(Deserialize)]
+struct RawExport {
+    guild: RawGuild,
+    channel: RawChannel,
+    messages: Vec<RawMessage>,
+}
+
+// Just enough of the export to tell it apart from other JSON exports
+#[derive(Deserialize)]
+struct RawExportHeader {
+    #[serde(rename = "guild")]
+    _guild: IgnoredAny,
+    #[serde(rename = "channel")]
+    _channel: IgnoredAny,
+}
+
+/// Returns true if the JSON is a DiscordChatExporter channel export
+pub fn is_discord_export(json_data: &str) -> bool {
+    serde_json::from_str::<RawExportHeader>(json_data).is_ok()
+}
+
+fn attachment_kind(file_name: &str) -> AttachmentKind {
+    let extension = Path::new(file_name)
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|extension| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("png") | Some("jpg") | Some("jpeg") | Some("webp") => AttachmentKind::Photo,
+        Some("gif") => AttachmentKind::Gif,
+        Some("mp4") | Some("mov") | Some("webm") => AttachmentKind::Video,
+        Some("mp3") | Some("ogg") | Some("wav") | Some("m4a") => AttachmentKind::Audio,
+        _ => AttachmentKind::File,
+    }
+}
+
+fn attachments(message: &RawMessage) -> Vec<Attachment> {
+    let mut attachments: Vec<Attachment> = message
+        .attachments
+        .iter()
+        .map(|attachment| Attachment {
+            kind: attachment_kind(&attachment.file_name),
+            uri: attachment.url.clone(),
+        })
+        .collect();
+    attachments.extend(message.stickers.iter().map(|sticker| Attachment {
+        kind: AttachmentKind::Sticker,
+        uri: sticker.source_url.clone(),
+    }));
+    attachments
+}
+
+// The first link preview, if it has anything to show
+fn embed_share(message: &RawMessage) -> Option<Share> {
+    let embed = message.embeds.first()?;
+    if embed.url.is_none() && embed.title.is_none() {
+        return None;
+    }
+    Some(Share {
+        link: embed.url.clone(),
+        share_text: embed.title.clone(),
+        original_content_owner: None,
+        expired_story: false,
+    })
+}
+
+fn parse_channel(export: &RawExport) -> Result<(Vec<Participant>, Vec<Message>)> {
+    let mut participants: Vec<Participant> = Vec::new();
+    let mut messages: Vec<Message> = Vec::new();
+
+    for message in export.messages.iter() {
+        // Everything else is joins, pins, calls, thread creation...
+        if message.r#type != "Default" && message.r#type != "Reply" {
+            continue;
+        }
+
+        let timestamp = DateTime::parse_from_rfc3339(&message.timestamp)
+            .map_err(|e| Error::Schema(format!("bad timestamp {:?}: {}", message.timestamp, e)))?
+            .with_timezone(&Utc);
+        let attachments = attachments(message);
+        let kind = match (attachments.first(), embed_share(message)) {
+            (Some(attachment), _) => attachment.kind.message_kind(),
+            (None, Some(share)) => MessageKind::Share(share),
+            (None, None) if message.content.is_empty() => continue,
+            (None, None) => MessageKind::Text,
+        };
+
+        let author = &message.author.name;
+        if !participants.iter().any(|p| &p.name == author) {
+            participants.push(Participant {
+                name: author.clone(),
+            });
+        }
+
+        messages.push(Message {
+            content: message.content.clone(),
+            kind,
+            attachments,
+            reactions: Vec::new(),
+            author: author.clone(),
+            timestamp,
+            id: Some(message.id.clone()),
+            reply_to: message
+                .reference
+                .as_ref()
+                .and_then(|reference| reference.message_id.clone())
+                .map(ReplyTo::Id),
+        });
+    }
+
+    Ok((participants, messages))
+}
+
+fn channel_name(export: &RawExport) -> String {
+    let name: String = format!("{}_{}", export.guild.name, export.channel.name)
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}_{}", name.to_lowercase(), export.channel.id)
+}
+
+/// DiscordChatExporter JSON exports, one conversation per channel
+#[derive(Default)]
+pub struct DiscordSource {
+    channels: Vec<RawExport>,
+}
+
+impl DiscordSource {
+    pub fn new() -> DiscordSource {
+        DiscordSource::default()
+    }
+
+    /// Opens a single channel export, or every `.json` export in a directory
+    pub fn open(path: &str) -> Result<DiscordSource> {
+        if Path::new(path).is_dir() {
+            return DiscordSource::from_archive(&mut Archive::open(path)?);
+        }
+
+        let mut json_data = String::new();
+        File::open(path)?.read_to_string(&mut json_data)?;
+
+        let mut source = DiscordSource::new();
+        source.add_channel(&json_data)?;
+        Ok(source)
+    }
+
+    /// Reads every channel export in the archive, skipping any other JSON
+    pub fn from_archive(archive: &mut Archive) -> Result<DiscordSource> {
+        let mut source = DiscordSource::new();
+        for (_, idx) in get_json_names(archive)? {
+            let json_data = archive.read_to_string(idx)?;
+            if is_discord_export(&json_data) {
+                source.add_channel(&json_data)?;
+            }
+        }
+
+        Ok(source)
+    }
+
+    pub fn add_channel(&mut self, json_data: &str) -> Result<()> {
+        self.channels.push(serde_json::from_str(json_data)?);
+        Ok(())
+    }
+}
+
+impl ChatSource for DiscordSource {
+    fn conversation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.channels.iter().map(channel_name).collect();
+        names.sort();
+        names
+    }
+
+    fn read_conversation(&mut self, name: &str) -> Result<Option<Conversation>> {
+        let export = match self
+            .channels
+            .iter()
+            .find(|export| channel_name(export) == name)
+        {
+            Some(export) => export,
+            None => return Ok(None),
+        };
+        let (participants, mut messages) = parse_channel(export)?;
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        messages.sort_by_key(|a| a.timestamp);
+        resolve_replies(&mut messages);
+        println!(
+            "Parsed {} messages from Discord channel {}",
+            messages.len(),
+            name
+        );
+
+        Ok(Some(Conversation {
+            name: String::from(name),
+            title: format!("{} - #{}", export.guild.name, export.channel.name),
+            participants,
+            messages,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"{
+        "guild": {"id": "1", "name": "Coraline"},
+        "channel": {"id": "22", "type": "GuildTextChat", "name": "general"},
+        "messages": [
+            {"id": "100", "type": "GuildMemberJoin", "timestamp": "2021-01-01T00:00:00+00:00",
+             "content": "", "author": {"id": "5", "name": "alice"}},
+            {"id": "101", "type": "Default", "timestamp": "2021-01-01T00:00:01.5+00:00",
+             "content": "hello", "author": {"id": "5", "name": "alice"}},
+            {"id": "102", "type": "Reply", "timestamp": "2021-01-01T00:00:03+00:00",
+             "content": "", "author": {"id": "6", "name": "bob"},
+             "attachments": [{"id": "9", "url": "https://cdn.example/a.png", "fileName": "a.png"}],
+             "reference": {"messageId": "101"}}
+        ]
+    }"#;
+
+    #[test]
+    fn test_discord_export() {
+        assert!(is_discord_export(EXPORT));
+        assert!(!is_discord_export(r#"{"chats": {"list": []}}"#));
+
+        let mut source = DiscordSource::new();
+        source.add_channel(EXPORT).unwrap();
+        assert_eq!(source.conversation_names(), vec!["coraline_general_22"]);
+
+        let conversation = source
+            .read_conversation("coraline_general_22")
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.title, "Coraline - #general");
+        assert_eq!(conversation.participants.len(), 2);
+        assert_eq!(conversation.messages.len(), 2);
+        assert_eq!(conversation.messages[0].content, "hello");
+        assert_eq!(conversation.messages[1].kind, MessageKind::Photo);
+        assert_eq!(
+            conversation.messages[1].reply_to,
+            Some(ReplyTo::Message {
+                author: String::from("alice"),
+                content: String::from("hello"),
+                timestamp: conversation.messages[0].timestamp,
+            })
+        );
+        assert_eq!(
+            conversation.messages[1].render_content(),
+            "PHOTOS: -https://cdn.example/a.png"
+        );
+    }
+}
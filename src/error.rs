@@ -0,0 +1,78 @@
+use std::fmt;
+
+/// Everything that can go wrong while reading a chat export
+#[derive(Debug)]
+pub enum Error {
+    Zip(zip::result::ZipError),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// Text that isn't valid UTF-8, or a broken `\u` escape
+    Encoding(String),
+    /// Valid JSON (or text) that isn't laid out the way the export should be
+    Schema(String),
+    /// An input glob that couldn't be parsed
+    Pattern(glob::PatternError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Zip(e) => write!(f, "zip error: {}", e),
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::Encoding(e) => write!(f, "encoding error: {}", e),
+            Error::Schema(e) => write!(f, "unexpected export layout: {}", e),
+            Error::Pattern(e) => write!(f, "invalid input pattern: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Zip(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Pattern(e) => Some(e),
+            Error::Encoding(_) | Error::Schema(_) => None,
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Error {
+        Error::Zip(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(e: glob::PatternError) -> Error {
+        Error::Pattern(e)
+    }
+}
+
+impl From<glob::GlobError> for Error {
+    fn from(e: glob::GlobError) -> Error {
+        Error::Io(e.into())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(e: std::string::FromUtf8Error) -> Error {
+        Error::Encoding(e.to_string())
+    }
+}
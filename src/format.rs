@@ -0,0 +1,928 @@
+use chrono::{DateTime, Datelike, Utc};
+use serde::Serialize;
+use std::io::{self, Write};
+
+use crate::tokenizer::Tokenizer;
+use crate::{Membership, Message, Participant, Reaction, ReplyTo, Segmenter};
+
+/// Written between the messages of a segment by the GPT-2 layout
+pub const GPT2_EOM: &str = "|EOM|";
+/// Written between segments by the GPT-2 layout
+pub const GPT2_EOC: &str = "<|endoftext|>";
+
+/// Names `formatter` accepts
+pub const FORMATS: &[&str] = &["gpt2", "chatml", "sharegpt"];
+
+/// A conversation split wherever it went quiet, ready to be written out
+pub struct Segmented<'a> {
+    pub segments: Vec<Vec<&'a Message>>,
+    membership: Membership,
+}
+
+impl<'a> Segmented<'a> {
+    /// `messages` must be sorted by timestamp. System events are left out
+    /// here if `options` drops them, so they don't hold segments together
+    pub fn new(
+        messages: &'a [Message],
+        participants: &[Participant],
+        segmenter: &Segmenter,
+        options: &FormatOptions,
+    ) -> Segmented<'a> {
+        let kept: Vec<&Message> = messages
+            .iter()
+            .filter(|message| {
+                options.events != EventStyle::Drop || message.kind.event_name().is_none()
+            })
+            .collect();
+        Segmented {
+            segments: segmenter
+                .split(&kept)
+                .into_iter()
+                .map(|segment| segment.to_vec())
+                .collect(),
+            // Dropped events still say who joined and left
+            membership: Membership::new(participants, messages),
+        }
+    }
+
+    /// Every message in every segment, in order
+    pub fn messages(&self) -> impl Iterator<Item = &'a Message> + '_ {
+        self.segments.iter().flatten().copied()
+    }
+
+    /// Splits segments that take up more than `max_tokens` into pieces that
+    /// don't, between messages, counting each piece as `formatter` writes
+    /// it: headers, separators, system prompts and all. Each piece after the
+    /// first starts with up to `overlap` messages from the end of the one
+    /// before, if they fit. Returns how many messages are over budget on
+    /// their own, and so are left alone in a piece that's still too long
+    pub fn fit_to_budget(
+        &mut self,
+        formatter: &dyn Formatter,
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+        overlap: usize,
+    ) -> usize {
+        let mut too_long = 0;
+        let mut pieces = Vec::new();
+        for segment in std::mem::take(&mut self.segments) {
+            let mut start = 0;
+            loop {
+                let end = self.fit_end(&segment, start, formatter, tokenizer, max_tokens);
+                if end == start + 1
+                    && self.count_tokens(&segment[start..end], formatter, tokenizer) > max_tokens
+                {
+                    too_long += 1;
+                }
+                pieces.push(segment[start..end].to_vec());
+                if end == segment.len() {
+                    break;
+                }
+
+                // Carry over as much of the end of that piece as fits
+                let mut carried = overlap.min(end - start);
+                while carried > 0
+                    && self.count_tokens(&segment[end - carried..=end], formatter, tokenizer)
+                        > max_tokens
+                {
+                    carried -= 1;
+                }
+                start = end - carried;
+            }
+        }
+        self.segments = pieces;
+        too_long
+    }
+
+    /// Replaces each segment with overlapping windows of it, the first
+    /// starting at its first message and each one after `stride` messages
+    /// later, until one reaches its end. Every message ends up in several
+    /// windows, with a different amount of what came before it in each.
+    /// Windows never span segments
+    pub fn sliding_windows(&mut self, size: WindowSize, stride: usize) {
+        let stride = stride.max(1);
+        let mut windows = Vec::new();
+        for segment in std::mem::take(&mut self.segments) {
+            for start in (0..segment.len()).step_by(stride) {
+                let end = match size {
+                    WindowSize::Messages(count) => (start + count.max(1)).min(segment.len()),
+                    WindowSize::Tokens(formatter, tokenizer, max_tokens) => {
+                        self.fit_end(&segment, start, formatter, tokenizer, max_tokens)
+                    }
+                };
+                windows.push(segment[start..end].to_vec());
+                if end == segment.len() {
+                    break;
+                }
+            }
+        }
+        self.segments = windows;
+    }
+
+    // How many tokens `piece` takes up as `formatter` writes it on its own.
+    // It's written from `segments`, which the caller has taken everything
+    // out of, so that it's written with this conversation's membership
+    fn count_tokens(
+        &mut self,
+        piece: &[&'a Message],
+        formatter: &dyn Formatter,
+        tokenizer: &Tokenizer,
+    ) -> usize {
+        self.segments = vec![piece.to_vec()];
+        let mut out = Vec::new();
+        formatter
+            .write_conversation(&mut out, self)
+            .expect("writing to a Vec can't fail");
+        self.segments.clear();
+        tokenizer.count(&String::from_utf8_lossy(&out))
+    }
+
+    // Where the longest piece of `segment` from `start` that fits in
+    // `max_tokens` ends: at least one message, however long. Pieces only
+    // take up more as messages are added, so it can be bisected
+    fn fit_end(
+        &mut self,
+        segment: &[&'a Message],
+        start: usize,
+        formatter: &dyn Formatter,
+        tokenizer: &Tokenizer,
+        max_tokens: usize,
+    ) -> usize {
+        let (mut low, mut high) = (start + 1, segment.len());
+        while low < high {
+            let mid = (low + high).div_ceil(2);
+            if self.count_tokens(&segment[start..mid], formatter, tokenizer) <= max_tokens {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        low
+    }
+
+    /// The people who were in the chat during `segment`
+    pub fn participants(&self, segment: &[&Message]) -> Vec<String> {
+        match (segment.first(), segment.last()) {
+            (Some(first), Some(last)) => self
+                .membership
+                .present_during(first.timestamp, last.timestamp),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// How much of a conversation `Segmented::sliding_windows` puts in each
+/// window
+#[derive(Clone, Copy)]
+pub enum WindowSize<'a> {
+    Messages(usize),
+    /// As many messages as fit in this many tokens, as the formatter writes
+    /// them
+    Tokens(&'a dyn Formatter, &'a Tokenizer, usize),
+}
+
+/// Writes segmented conversations in one training format. Each output file
+/// gets one call per conversation that goes into it
+pub trait Formatter {
+    fn write_conversation(&self, out: &mut dyn Write, conversation: &Segmented) -> io::Result<()>;
+
+    /// What the files it writes should end in, if anything
+    fn extension(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// The formatter called `name`, one of `FORMATS`
+pub fn formatter(name: &str, options: FormatOptions) -> Option<Box<dyn Formatter>> {
+    match name {
+        "gpt2" => Some(Box::new(Gpt2Formatter::new(options))),
+        "chatml" => Some(Box::new(ChatMLFormatter::new(options))),
+        "sharegpt" => Some(Box::new(ShareGPTFormatter::new(options))),
+        _ => None,
+    }
+}
+
+/// How formatters write calls and system events
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EventStyle {
+    /// Like any other message, as the chat app worded it
+    #[default]
+    Text,
+    /// Leave them out
+    Drop,
+    /// Replace them with a special token, e.g. `<|joined|>`
+    Token,
+    /// As their own kind of line, with `EVENT` in place of the author
+    Separate,
+}
+
+/// How formatters show what a reply replies to
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplyStyle {
+    /// Only by what came before it
+    #[default]
+    Ignore,
+    /// Start the reply with the message it quotes, e.g.
+    /// `|Replying to Alice: knock knock| who's there`
+    Inline,
+    /// Write only (quoted message, reply) pairs instead of the conversation.
+    /// Only the GPT-2 layout does this
+    Pairs,
+}
+
+/// Optional extras for formatters. Dropping system events applies to every
+/// format, since it happens before a conversation is segmented
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    /// Follow each message with its reactions, e.g. `|Reactions: ❤ Bob|`
+    pub reactions: bool,
+    pub events: EventStyle,
+    pub replies: ReplyStyle,
+    /// Whose messages chat formats train the model to write
+    pub persona: Option<String>,
+    /// Put at the start of every example by chat formats
+    pub system_prompt: Option<String>,
+}
+
+fn format_line(
+    conversation_timestamp: DateTime<Utc>,
+    timestamp: DateTime<Utc>,
+    author: &str,
+    content: &str,
+) -> String {
+    format!(
+        "|{} {} {} {}|: {}\n",
+        conversation_timestamp.month(),
+        conversation_timestamp.year(),
+        timestamp
+            .signed_duration_since(conversation_timestamp)
+            .num_seconds(),
+        author,
+        content
+    )
+}
+
+/// Every resolved reply together with the message it quotes, each pair
+/// separated by `eoc`
+fn format_reply_pairs(conversation: &[&Message], eom: &str, eoc: &str) -> String {
+    conversation
+        .iter()
+        .filter_map(|message| match &message.reply_to {
+            Some(ReplyTo::Message {
+                author,
+                content,
+                timestamp,
+            }) => Some(
+                [
+                    format_line(*timestamp, *timestamp, author, content),
+                    format_line(
+                        *timestamp,
+                        message.timestamp,
+                        &message.author,
+                        &message.render_content(),
+                    ),
+                ]
+                .join(eom),
+            ),
+            _ => None,
+        })
+        .collect::<Vec<String>>()
+        .join(eoc)
+}
+
+fn format_reactions(reactions: &[Reaction]) -> String {
+    format!(
+        " |Reactions: {}|",
+        reactions
+            .iter()
+            .map(|r| format!("{} {}", r.reaction, r.actor))
+            .collect::<Vec<String>>()
+            .join(", ")
+    )
+}
+
+/// Who a message should be attributed to and what it says, with the
+/// reactions, replies and events `options` asks for
+fn render_message<'a>(message: &'a Message, options: &FormatOptions) -> (&'a str, String) {
+    let mut author = message.author.as_str();
+    let mut content = match (message.kind.event_name(), options.events) {
+        (Some(event_name), EventStyle::Token) => format!("<|{}|>", event_name),
+        (Some(event_name), EventStyle::Separate) => {
+            author = "EVENT";
+            match message.render_content() {
+                // Unsent messages don't always say who unsent them
+                content if content.is_empty() => String::from(event_name),
+                content => content,
+            }
+        }
+        _ => message.render_content(),
+    };
+    if options.reactions && !message.reactions.is_empty() {
+        content.push_str(&format_reactions(&message.reactions));
+    }
+    if let (
+        ReplyStyle::Inline,
+        Some(ReplyTo::Message {
+            author,
+            content: quoted,
+            ..
+        }),
+    ) = (options.replies, &message.reply_to)
+    {
+        content = format!("|Replying to {}: {}| {}", author, quoted, content);
+    }
+
+    (author, content)
+}
+
+/// The layout the Coraline GPT-2 scripts train on: a participants header,
+/// then `|month year seconds author|: content` lines separated by `eom`,
+/// with segments separated by `eoc`
+#[derive(Debug, Clone)]
+pub struct Gpt2Formatter {
+    pub eom: String,
+    pub eoc: String,
+    pub options: FormatOptions,
+}
+
+impl Gpt2Formatter {
+    pub fn new(options: FormatOptions) -> Gpt2Formatter {
+        Gpt2Formatter {
+            eom: String::from(GPT2_EOM),
+            eoc: String::from(GPT2_EOC),
+            options,
+        }
+    }
+
+    fn format_message(&self, conversation_timestamp: DateTime<Utc>, message: &Message) -> String {
+        let (author, content) = render_message(message, &self.options);
+        format_line(conversation_timestamp, message.timestamp, author, &content)
+    }
+}
+impl Formatter for Gpt2Formatter {
+    fn write_conversation(&self, out: &mut dyn Write, conversation: &Segmented) -> io::Result<()> {
+        if self.options.replies == ReplyStyle::Pairs {
+            let messages: Vec<&Message> = conversation.messages().collect();
+            return out.write_all(format_reply_pairs(&messages, &self.eom, &self.eoc).as_bytes());
+        }
+
+        for (i, segment) in conversation.segments.iter().enumerate() {
+            if i > 0 {
+                out.write_all(self.eoc.as_bytes())?;
+            }
+            let conversation_timestamp = segment[0].timestamp;
+
+            // Let's remind GPT-3 at the start of each conversation, of just
+            // the people who were in the chat at the time
+            let header = format!(
+                "|Participants: {:?}|\n",
+                conversation.participants(segment).join(", ")
+            );
+
+            let mut current_conversation_strs = vec![header];
+            for message in segment {
+                current_conversation_strs
+                    .push(self.format_message(conversation_timestamp, message));
+            }
+            out.write_all(current_conversation_strs.join(&self.eom).as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One segment as turns for chat formats: whether it's the persona's, and
+/// what was said. Everyone else's messages start with their name in group
+/// chats. With `merge`, turns alternate strictly, starting with everyone
+/// else: consecutive messages on the same side are merged into one turn,
+/// and anything the persona said before anyone else is left out. `None` if
+/// the persona said nothing, since there'd be nothing to learn from it
+fn chat_turns(
+    conversation: &Segmented,
+    segment: &[&Message],
+    options: &FormatOptions,
+    merge: bool,
+) -> Option<Vec<(bool, String)>> {
+    let persona = options.persona.as_deref();
+    let group = conversation.participants(segment).len() > 2;
+    let mut turns: Vec<(bool, String)> = Vec::new();
+
+    for message in segment {
+        let (author, content) = render_message(message, options);
+        let is_persona = Some(author) == persona;
+        if merge && is_persona && turns.is_empty() {
+            continue;
+        }
+
+        let content = if group && !is_persona {
+            format!("{}: {}", author, content)
+        } else {
+            content
+        };
+        match turns.last_mut() {
+            Some((last_is_persona, turn)) if merge && *last_is_persona == is_persona => {
+                turn.push('\n');
+                turn.push_str(&content);
+            }
+            _ => turns.push((is_persona, content)),
+        }
+    }
+
+    // Nor from what was said after the persona's last message
+    let last = turns.iter().rposition(|(is_persona, _)| *is_persona)?;
+    turns.truncate(last + 1);
+    Some(turns)
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct ChatExample<'a> {
+    messages: Vec<ChatMessage<'a>>,
+}
+
+/// OpenAI-style chat fine-tuning JSONL, one `{"messages": [...]}` line per
+/// segment. The persona's messages are the `assistant`'s and everyone
+/// else's are the `user`'s, starting with who said it in group chats
+#[derive(Debug, Clone)]
+pub struct ChatMLFormatter {
+    pub options: FormatOptions,
+}
+
+impl ChatMLFormatter {
+    pub fn new(options: FormatOptions) -> ChatMLFormatter {
+        ChatMLFormatter { options }
+    }
+}
+
+impl Formatter for ChatMLFormatter {
+    fn write_conversation(&self, out: &mut dyn Write, conversation: &Segmented) -> io::Result<()> {
+        for segment in conversation.segments.iter() {
+            let turns = match chat_turns(conversation, segment, &self.options, false) {
+                Some(turns) => turns,
+                None => continue,
+            };
+            let system = self.options.system_prompt.iter().map(|prompt| ChatMessage {
+                role: "system",
+                content: prompt,
+            });
+            let messages = system
+                .chain(turns.iter().map(|(is_persona, content)| ChatMessage {
+                    role: if *is_persona { "assistant" } else { "user" },
+                    content,
+                }))
+                .collect();
+
+            serde_json::to_writer(&mut *out, &ChatExample { messages }).map_err(io::Error::from)?;
+            out.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn extension(&self) -> Option<&'static str> {
+        Some("jsonl")
+    }
+}
+
+#[derive(Serialize)]
+struct ShareGPTTurn<'a> {
+    from: &'static str,
+    value: &'a str,
+}
+
+#[derive(Serialize)]
+struct ShareGPTExample<'a> {
+    conversations: Vec<ShareGPTTurn<'a>>,
+}
+
+/// ShareGPT-style JSONL, one `{"conversations": [...]}` line per segment.
+/// The persona is `gpt` and everyone else is `human`, taking turns and
+/// always starting with `human`. Consecutive messages by everyone else are
+/// merged into one turn, starting with who said each in group chats
+#[derive(Debug, Clone)]
+pub struct ShareGPTFormatter {
+    pub options: FormatOptions,
+}
+
+impl ShareGPTFormatter {
+    pub fn new(options: FormatOptions) -> ShareGPTFormatter {
+        ShareGPTFormatter { options }
+    }
+}
+
+impl Formatter for ShareGPTFormatter {
+    fn write_conversation(&self, out: &mut dyn Write, conversation: &Segmented) -> io::Result<()> {
+        for segment in conversation.segments.iter() {
+            let turns = match chat_turns(conversation, segment, &self.options, true) {
+                Some(turns) => turns,
+                None => continue,
+            };
+            let system = self
+                .options
+                .system_prompt
+                .iter()
+                .map(|prompt| ShareGPTTurn {
+                    from: "system",
+                    value: prompt,
+                });
+            let conversations = system
+                .chain(turns.iter().map(|(is_persona, value)| ShareGPTTurn {
+                    from: if *is_persona { "gpt" } else { "human" },
+                    value,
+                }))
+                .collect();
+
+            serde_json::to_writer(&mut *out, &ShareGPTExample { conversations })
+                .map_err(io::Error::from)?;
+            out.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn extension(&self) -> Option<&'static str> {
+        Some("jsonl")
+    }
+}
+
+/// A whole conversation in the GPT-2 layout, with custom separators
+pub fn format_conversation(
+    messages: &[Message],
+    participants: &[Participant],
+    eom: &str,
+    eoc: &str,
+    options: &FormatOptions,
+) -> String {
+    let formatter = Gpt2Formatter {
+        eom: String::from(eom),
+        eoc: String::from(eoc),
+        options: options.clone(),
+    };
+    let mut out = Vec::new();
+    formatter
+        .write_conversation(
+            &mut out,
+            &Segmented::new(messages, participants, &Segmenter::default(), options),
+        )
+        .expect("writing to a Vec can't fail");
+    String::from_utf8(out).expect("everything written was a String")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageKind, SystemEvent};
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_format_reactions() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let mut joke = Message::text("Alice", "knock knock", start);
+        joke.reactions.push(Reaction {
+            reaction: String::from("😆"),
+            actor: String::from("Bob"),
+        });
+        let conversation = vec![
+            joke,
+            Message::text("Bob", "who's there", start + Duration::seconds(5)),
+            Message::text("Alice", "later", start + Duration::hours(1)),
+        ];
+        let participants = vec![Participant {
+            name: String::from("Alice"),
+        }];
+
+        let plain = format_conversation(
+            &conversation,
+            &participants,
+            "|EOM|",
+            "<|endoftext|>",
+            &FormatOptions::default(),
+        );
+        assert_eq!(
+            plain,
+            "|Participants: \"Alice, Bob\"|\n|EOM|\
+             |1 2021 0 Alice|: knock knock\n|EOM|\
+             |1 2021 5 Bob|: who's there\n<|endoftext|>\
+             |Participants: \"Alice, Bob\"|\n|EOM|\
+             |1 2021 0 Alice|: later\n"
+        );
+
+        let options = FormatOptions {
+            reactions: true,
+            ..FormatOptions::default()
+        };
+        let annotated = format_conversation(
+            &conversation,
+            &participants,
+            "|EOM|",
+            "<|endoftext|>",
+            &options,
+        );
+        assert!(annotated.contains("|1 2021 0 Alice|: knock knock |Reactions: 😆 Bob|\n|EOM|"));
+        assert!(annotated.contains("|1 2021 5 Bob|: who's there\n"));
+    }
+
+    #[test]
+    fn test_format_events() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let mut joined = Message::text("Alice", "Alice added Bob to the group.", start);
+        joined.kind = MessageKind::System(SystemEvent::Joined(vec![String::from("Bob")]));
+        let conversation = vec![
+            joined,
+            Message::text("Bob", "hi", start + Duration::seconds(5)),
+            Message::text("Alice", "later", start + Duration::hours(1)),
+        ];
+        let format = |events| {
+            let options = FormatOptions {
+                events,
+                ..FormatOptions::default()
+            };
+            format_conversation(&conversation, &[], "|EOM|", "<|endoftext|>", &options)
+        };
+
+        let header = "|Participants: \"Alice, Bob\"|\n|EOM|";
+        assert!(format(EventStyle::Text)
+            .starts_with(&format!("{}|1 2021 0 Alice|: Alice added Bob", header)));
+        assert!(format(EventStyle::Drop).starts_with(&format!("{}|1 2021 0 Bob|: hi\n", header)));
+        assert!(format(EventStyle::Token)
+            .starts_with(&format!("{}|1 2021 0 Alice|: <|joined|>\n", header)));
+        assert!(format(EventStyle::Separate)
+            .starts_with(&format!("{}|1 2021 0 EVENT|: Alice added Bob", header)));
+    }
+
+    #[test]
+    fn test_formatter_by_name() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let conversation = vec![
+            Message::text("Alice", "hi", start),
+            Message::text("Bob", "hey", start + Duration::seconds(3)),
+        ];
+        let options = FormatOptions::default();
+
+        let mut out = Vec::new();
+        formatter("gpt2", options.clone())
+            .unwrap()
+            .write_conversation(
+                &mut out,
+                &Segmented::new(&conversation, &[], &Segmenter::default(), &options),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format_conversation(&conversation, &[], GPT2_EOM, GPT2_EOC, &options)
+        );
+        assert!(formatter("gpt-5", options).is_none());
+    }
+
+    #[test]
+    fn test_chatml() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let options = FormatOptions {
+            persona: Some(String::from("Bob")),
+            system_prompt: Some(String::from("You are Bob.")),
+            ..FormatOptions::default()
+        };
+        let write = |conversation: &[Message]| -> Vec<serde_json::Value> {
+            let mut out = Vec::new();
+            ChatMLFormatter::new(options.clone())
+                .write_conversation(
+                    &mut out,
+                    &Segmented::new(conversation, &[], &Segmenter::default(), &options),
+                )
+                .unwrap();
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+
+        let lines = write(&[
+            Message::text("Alice", "hi", start),
+            Message::text("Bob", "hey", start + Duration::seconds(3)),
+            Message::text("Alice", "bye", start + Duration::seconds(6)),
+            Message::text("Alice", "later", start + Duration::days(1)),
+        ]);
+        // The last segment has nothing from Bob, so it's left out
+        assert_eq!(
+            lines,
+            vec![serde_json::json!({"messages": [
+                {"role": "system", "content": "You are Bob."},
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": "hey"},
+            ]})]
+        );
+
+        let lines = write(&[
+            Message::text("Alice", "hi", start),
+            Message::text("Carol", "hello?", start + Duration::seconds(1)),
+            Message::text("Bob", "hey", start + Duration::seconds(3)),
+        ]);
+        assert_eq!(
+            lines[0]["messages"][2],
+            serde_json::json!({"role": "user", "content": "Carol: hello?"})
+        );
+        assert_eq!(
+            lines[0]["messages"][3],
+            serde_json::json!({"role": "assistant", "content": "hey"})
+        );
+    }
+
+    #[test]
+    fn test_sharegpt() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let conversation = vec![
+            Message::text("Alice", "hi", start),
+            Message::text("Alice", "you there?", start + Duration::seconds(2)),
+            Message::text("Bob", "hey", start + Duration::seconds(3)),
+            Message::text("Bob", "what's up", start + Duration::seconds(4)),
+            Message::text("Alice", "bye", start + Duration::seconds(6)),
+        ];
+        let options = FormatOptions {
+            persona: Some(String::from("Bob")),
+            ..FormatOptions::default()
+        };
+        let write = |conversation: &[Message]| -> serde_json::Value {
+            let mut out = Vec::new();
+            ShareGPTFormatter::new(options.clone())
+                .write_conversation(
+                    &mut out,
+                    &Segmented::new(conversation, &[], &Segmenter::default(), &options),
+                )
+                .unwrap();
+            serde_json::from_str(String::from_utf8(out).unwrap().trim_end()).unwrap()
+        };
+
+        assert_eq!(
+            write(&conversation),
+            serde_json::json!({"conversations": [
+                {"from": "human", "value": "hi\nyou there?"},
+                {"from": "gpt", "value": "hey\nwhat's up"},
+            ]})
+        );
+
+        // Bob speaking first is left out, and Alice and Carol share a turn
+        let conversation = vec![
+            Message::text("Bob", "morning", start),
+            Message::text("Alice", "hi", start + Duration::seconds(1)),
+            Message::text("Carol", "hello", start + Duration::seconds(2)),
+            Message::text("Bob", "hey", start + Duration::seconds(3)),
+        ];
+        assert_eq!(
+            write(&conversation),
+            serde_json::json!({"conversations": [
+                {"from": "human", "value": "Alice: hi\nCarol: hello"},
+                {"from": "gpt", "value": "hey"},
+            ]})
+        );
+    }
+
+    // No merges, so every byte is a token
+    fn byte_tokenizer() -> Tokenizer {
+        let vocab = crate::tokenizer::byte_encoder()
+            .into_iter()
+            .enumerate()
+            .map(|(byte, c)| (c.to_string(), byte as u32))
+            .collect();
+        Tokenizer::new(vocab, Vec::new())
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let tokenizer = byte_tokenizer();
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let long = "d".repeat(30);
+        let conversation: Vec<Message> = ["a", "bb", "c", long.as_str(), "e"]
+            .iter()
+            .enumerate()
+            .map(|(i, content)| Message::text("A", content, start + Duration::seconds(i as i64)))
+            .collect();
+        let options = FormatOptions::default();
+        let formatter = Gpt2Formatter::new(options.clone());
+        let lengths = |max_tokens, overlap| {
+            let mut segmented = Segmented::new(&conversation, &[], &Segmenter::default(), &options);
+            let too_long = segmented.fit_to_budget(&formatter, &tokenizer, max_tokens, overlap);
+            let contents: Vec<Vec<&str>> = segmented
+                .segments
+                .iter()
+                .map(|segment| segment.iter().map(|m| m.content.as_str()).collect())
+                .collect();
+            (contents, too_long)
+        };
+
+        // The header is 20 tokens, and "|EOM||1 2021 0 A|: a\n" 21
+        assert_eq!(
+            lengths(65, 0),
+            (
+                vec![vec!["a", "bb"], vec!["c"], vec![long.as_str()], vec!["e"]],
+                1
+            )
+        );
+        assert_eq!(
+            lengths(65, 1),
+            (
+                vec![
+                    vec!["a", "bb"],
+                    vec!["bb", "c"],
+                    vec![long.as_str()],
+                    vec!["e"]
+                ],
+                1
+            )
+        );
+        assert_eq!(lengths(200, 1).0.len(), 1);
+    }
+
+    #[test]
+    fn test_fit_to_budget_formats() {
+        let tokenizer = byte_tokenizer();
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let conversation: Vec<Message> = (0..40)
+            .map(|i| {
+                let author = ["Alice", "Bob", "Carol"][i % 3];
+                let content = "so ".repeat(i % 7 + 1);
+                Message::text(author, &content, start + Duration::seconds(i as i64))
+            })
+            .collect();
+        let options = FormatOptions {
+            reactions: true,
+            persona: Some(String::from("Bob")),
+            system_prompt: Some(String::from("You are Bob.")),
+            ..FormatOptions::default()
+        };
+
+        let formatters: Vec<Box<dyn Formatter>> = FORMATS
+            .iter()
+            .map(|name| formatter(name, options.clone()).unwrap())
+            .collect();
+        for formatter in formatters.iter() {
+            let mut segmented = Segmented::new(&conversation, &[], &Segmenter::default(), &options);
+            assert_eq!(
+                segmented.fit_to_budget(formatter.as_ref(), &tokenizer, 150, 2),
+                0
+            );
+            assert!(segmented.segments.len() > 1);
+
+            // Each piece, written the way it will be, is within budget
+            let pieces = std::mem::take(&mut segmented.segments);
+            for piece in pieces {
+                segmented.segments = vec![piece];
+                let mut out = Vec::new();
+                formatter.write_conversation(&mut out, &segmented).unwrap();
+                assert!(tokenizer.count(&String::from_utf8(out).unwrap()) <= 150);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sliding_windows() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let at = |seconds| start + Duration::seconds(seconds);
+        let conversation = vec![
+            Message::text("A", "1", at(0)),
+            Message::text("B", "2", at(1)),
+            Message::text("A", "3", at(2)),
+            Message::text("B", "4", at(3)),
+            Message::text("A", "5", at(4)),
+            // A new segment
+            Message::text("B", "6", at(3600)),
+        ];
+        let options = FormatOptions::default();
+        let windows = |size, stride| {
+            let mut segmented = Segmented::new(&conversation, &[], &Segmenter::default(), &options);
+            segmented.sliding_windows(size, stride);
+            segmented
+                .segments
+                .iter()
+                .map(|window| window.iter().map(|m| m.content.as_str()).collect())
+                .collect::<Vec<Vec<&str>>>()
+        };
+
+        assert_eq!(
+            windows(WindowSize::Messages(3), 1),
+            vec![
+                vec!["1", "2", "3"],
+                vec!["2", "3", "4"],
+                vec!["3", "4", "5"],
+                vec!["6"]
+            ]
+        );
+        assert_eq!(
+            windows(WindowSize::Messages(2), 2),
+            vec![vec!["1", "2"], vec!["3", "4"], vec!["5"], vec!["6"]]
+        );
+
+        let tokenizer = byte_tokenizer();
+        let formatter = Gpt2Formatter::new(options.clone());
+        // The header is 23 tokens, and "|EOM||1 2021 0 A|: 1\n" 21
+        assert_eq!(
+            windows(WindowSize::Tokens(&formatter, &tokenizer, 70), 2),
+            vec![vec!["1", "2"], vec!["3", "4"], vec!["5"], vec!["6"]]
+        );
+    }
+}
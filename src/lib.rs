@@ -1,14 +1,39 @@
-use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use multimap::MultiMap;
 use rand::Rng;
 use rand_pcg::Pcg64Mcg;
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::Path;
 
+pub use archive::Archive;
+pub use error::{Error, Result};
+pub use format::{
+    format_conversation, EventStyle, FormatOptions, Formatter, Gpt2Formatter, ReplyStyle,
+    Segmented, WindowSize,
+};
+pub use membership::Membership;
+pub use segment::{SegmentPolicy, Segmenter};
+
+pub mod archive;
+pub mod authors;
+pub mod discord;
+pub mod error;
+pub mod format;
+pub mod membership;
+pub mod persona;
+pub mod preference;
+pub mod segment;
+pub mod slack;
+pub mod stats;
+pub mod telegram;
+pub mod tokenizer;
+pub mod whatsapp;
+
 //use mimalloc::MiMalloc;
 
 //#[global_allocator]
@@ -17,24 +42,276 @@ use std::path::Path;
 // AFK for more than 10 minutes means new conversation
 pub const CONVERSATION_TIMEOUT: i64 = 10 * 60;
 
-pub const TRAIN_TEST_TIMEOUT: i64 = 1;
-
 #[derive(Debug, Clone)]
 pub struct Message {
+    /// What the author wrote. Empty for messages that are only media
     pub content: String,
+    pub kind: MessageKind,
+    pub attachments: Vec<Attachment>,
+    /// Everyone who reacted to the message, and with what
+    pub reactions: Vec<Reaction>,
     pub author: String,
     pub timestamp: DateTime<Utc>,
+    /// The platform's id for the message, where it has one
+    pub id: Option<String>,
+    /// The message this one replies to, if the author quoted one
+    pub reply_to: Option<ReplyTo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyTo {
+    /// Id of the message replied to, before `resolve_replies` looks it up
+    Id(String),
+    /// What the export remembers of a message it has no id for
+    Snippet {
+        author: Option<String>,
+        content: String,
+        timestamp: Option<DateTime<Utc>>,
+    },
+    /// The message replied to, once found in the conversation. It's copied
+    /// so that it's still there once the conversation has been split up
+    Message {
+        author: String,
+        content: String,
+        timestamp: DateTime<Utc>,
+    },
+}
+
+/// What a message is, independently of whether it has any text
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageKind {
+    Text,
+    Photo,
+    Video,
+    Gif,
+    Sticker,
+    Audio,
+    File,
+    /// A link, post or story shared into the chat
+    Share(Share),
+    /// A voice or video call, with its duration in seconds if it connected
+    Call(Option<i64>),
+    /// Something the chat app wrote rather than a person
+    System(SystemEvent),
+}
+
+impl MessageKind {
+    /// Short name of calls and system events, e.g. for special tokens
+    pub fn event_name(&self) -> Option<&'static str> {
+        match self {
+            MessageKind::Call(_) => Some("call"),
+            MessageKind::System(event) => Some(match event {
+                SystemEvent::Joined(_) => "joined",
+                SystemEvent::Left(_) => "left",
+                SystemEvent::Nickname => "nickname",
+                SystemEvent::Theme => "theme",
+                SystemEvent::Unsent => "unsent",
+                SystemEvent::Other => "event",
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SystemEvent {
+    /// People added to the chat
+    Joined(Vec<String>),
+    /// People who left or were removed from the chat
+    Left(Vec<String>),
+    /// Someone's nickname was set or cleared
+    Nickname,
+    /// The chat's theme, emoji, name or photo changed
+    Theme,
+    /// A message that was taken back
+    Unsent,
+    Other,
+}
+
+// Messenger writes these as ordinary "Generic" messages, starting with the
+// sender's name
+const NICKNAME_PHRASES: &[&str] = &[
+    "set the nickname",
+    "set your nickname",
+    "set his own nickname",
+    "set her own nickname",
+    "set their own nickname",
+    "cleared the nickname",
+    "cleared your nickname",
+    "cleared his own nickname",
+    "cleared her own nickname",
+    "cleared their own nickname",
+];
+const THEME_PHRASES: &[&str] = &[
+    "changed the theme",
+    "changed the chat theme",
+    "set the emoji to",
+    "changed the emoji",
+    "changed the group photo",
+    "named the group",
+    "changed the chat colors",
+];
+const UNSENT_PHRASES: &[&str] = &["unsent a message"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachmentKind {
+    Photo,
+    Video,
+    Gif,
+    Sticker,
+    Audio,
+    File,
+}
+
+impl AttachmentKind {
+    /// Header used for this kind of media in rendered messages
+    pub fn header(self) -> &'static str {
+        match self {
+            AttachmentKind::Photo => "PHOTOS",
+            AttachmentKind::Video => "VIDEOS",
+            AttachmentKind::Gif => "GIFS",
+            AttachmentKind::Sticker => "STICKER",
+            AttachmentKind::Audio => "AUDIO",
+            AttachmentKind::File => "FILES",
+        }
+    }
+
+    /// The message kind for a message that's only this kind of media
+    pub fn message_kind(self) -> MessageKind {
+        match self {
+            AttachmentKind::Photo => MessageKind::Photo,
+            AttachmentKind::Video => MessageKind::Video,
+            AttachmentKind::Gif => MessageKind::Gif,
+            AttachmentKind::Sticker => MessageKind::Sticker,
+            AttachmentKind::Audio => MessageKind::Audio,
+            AttachmentKind::File => MessageKind::File,
+        }
+    }
+}
+
+/// A file sent with a message. `uri` is a path within the export, or a URL
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub kind: AttachmentKind,
+    pub uri: String,
+}
+
+impl Message {
+    /// A plain text message
+    pub fn text(author: &str, content: &str, timestamp: DateTime<Utc>) -> Message {
+        Message {
+            content: String::from(content),
+            kind: MessageKind::Text,
+            attachments: Vec::new(),
+            reactions: Vec::new(),
+            author: String::from(author),
+            timestamp,
+            id: None,
+            reply_to: None,
+        }
+    }
+
+    /// Renders the message as a single line of text. Media is written as its
+    /// header followed by every uri, e.g. `PHOTOS: -a.jpg-b.jpg`, or
+    /// `PHOTOS: omitted` if the export left the files out
+    pub fn render_content(&self) -> String {
+        match &self.kind {
+            // Replies to Instagram stories only make sense next to the story
+            MessageKind::Share(share) if share.is_story() && !self.content.is_empty() => {
+                format!("{} {}", share.placeholder(), self.content)
+            }
+            _ if !self.content.is_empty() => self.content.clone(),
+            MessageKind::Text | MessageKind::System(_) => String::new(),
+            MessageKind::Share(share) => share.placeholder(),
+            MessageKind::Call(Some(duration)) => format!("CALL: {}s", duration),
+            MessageKind::Call(None) => String::from("CALL: missed"),
+            kind => {
+                let header = match self.attachments.first() {
+                    Some(attachment) => attachment.kind.header(),
+                    None => match kind {
+                        MessageKind::Photo => "PHOTOS",
+                        MessageKind::Video => "VIDEOS",
+                        MessageKind::Gif => "GIFS",
+                        MessageKind::Sticker => "STICKER",
+                        MessageKind::Audio => "AUDIO",
+                        _ => "FILES",
+                    },
+                };
+                if self.attachments.is_empty() {
+                    format!("{}: omitted", header)
+                } else {
+                    format!(
+                        "{}: {}",
+                        header,
+                        self.attachments
+                            .iter()
+                            .fold(String::new(), |a, b| format!("{}-{}", a, b.uri))
+                    )
+                }
+            }
+        }
+    }
 }
 
 pub trait HasURI {
-    fn uri<'a>(self: &'a Self) -> &'a str;
-    fn header<'a>(self: &'a Self) -> &'static str;
+    fn uri(&self) -> &str;
+    fn kind(&self) -> AttachmentKind;
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Participant {
     pub name: String,
 }
+
+/// Every part of a single chat thread, merged and sorted by timestamp
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    /// Identifier used to select the conversation and to name output files
+    pub name: String,
+    pub title: String,
+    pub participants: Vec<Participant>,
+    pub messages: Vec<Message>,
+}
+
+/// A chat export that the rest of the pipeline can pull conversations from.
+/// Implement this to support a new chat platform -- formatting and splitting
+/// only ever see the `Conversation`s it produces
+pub trait ChatSource {
+    /// Names of every conversation in this source, in a stable order
+    fn conversation_names(&self) -> Vec<String>;
+
+    /// Reads every part of the named conversation. Returns `Ok(None)` if
+    /// there is no such conversation, or if it doesn't contain any messages
+    fn read_conversation(&mut self, name: &str) -> Result<Option<Conversation>>;
+
+    /// Messages left out of the conversations read so far because they
+    /// couldn't be decoded. Only lenient sources ever skip anything
+    fn take_skipped(&mut self) -> Vec<SkippedMessage> {
+        Vec::new()
+    }
+}
+
+/// How to handle a single message that can't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ParseMode {
+    /// Fail the whole thread, to validate an export
+    #[default]
+    Strict,
+    /// Leave the message out and keep going. Only Facebook and Instagram
+    /// threads are decoded message by message
+    Lenient,
+}
+
+/// A message that was left out in lenient mode
+#[derive(Debug)]
+pub struct SkippedMessage {
+    /// Path of the thread file within its export
+    pub path: String,
+    /// Position of the message in that file
+    pub index: usize,
+    pub error: Error,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Photo {
     pub uri: String,
@@ -52,22 +329,69 @@ pub struct Thumbnail {
 pub struct Video {
     pub uri: String,
     pub creation_timestamp: i64,
-    pub thumbnail: Thumbnail,
+    // Instagram doesn't export video thumbnails
+    pub thumbnail: Option<Thumbnail>,
 }
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Reaction {
     pub reaction: String,
     pub actor: String,
 }
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Share {
     pub link: Option<String>,
+    pub share_text: Option<String>,
+    // Instagram only -- the account that posted the shared content
+    pub original_content_owner: Option<String>,
+    // Replies to Instagram stories that have since expired only keep the
+    // story's text, in `share_text`
+    #[serde(skip)]
+    pub expired_story: bool,
+}
+
+impl Share {
+    /// Instagram story replies are shares that link to the story
+    pub fn is_story(&self) -> bool {
+        match &self.link {
+            Some(link) => link.contains("instagram.com/stories/"),
+            None => self.expired_story,
+        }
+    }
+
+    fn placeholder(&self) -> String {
+        let header = if self.is_story() { "STORY" } else { "SHARE" };
+        let shared = self.link.as_ref().or(self.share_text.as_ref());
+        let mut placeholder = format!("{}: -{}", header, shared.map_or("", String::as_str));
+        if let Some(owner) = &self.original_content_owner {
+            placeholder.push_str(&format!(" ({})", owner));
+        }
+        placeholder
+    }
 }
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Sticker {
     pub uri: String,
 }
 #[derive(Serialize, Deserialize, Clone)]
+pub struct AudioFile {
+    pub uri: String,
+}
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AttachedFile {
+    pub uri: String,
+}
+// Replies either quote the whole message, or just a snippet of its text
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum RawReply {
+    Snippet(String),
+    Message {
+        sender_name: Option<String>,
+        content: Option<String>,
+        timestamp_ms: Option<i64>,
+    },
+}
+#[derive(Serialize, Deserialize, Clone)]
 pub struct RawMessage {
     sender_name: String,
     timestamp_ms: i64,
@@ -76,82 +400,75 @@ pub struct RawMessage {
     sticker: Option<Sticker>,
     gifs: Option<Vec<Gif>>,
     videos: Option<Vec<Video>>,
+    audio_files: Option<Vec<AudioFile>>,
+    files: Option<Vec<AttachedFile>>,
     reactions: Option<Vec<Reaction>>,
     share: Option<Share>,
+    // Instagram only, on replies to stories that have since expired
+    story_share: Option<String>,
+    call_duration: Option<i64>,
+    // Who was added or removed, on Subscribe and Unsubscribe messages
+    users: Option<Vec<Participant>>,
+    #[serde(default)]
+    is_unsent: bool,
+    replied_to: Option<RawReply>,
+    // Instagram doesn't export message types
+    #[serde(default)]
     r#type: String,
 }
 
-impl HasURI for Sticker {
-    fn uri<'a>(self: &'a Self) -> &'a str {
-        &self.uri
-    }
+macro_rules! has_uri {
+    ($($raw:ty => $kind:ident),*) => {
+        $(impl HasURI for $raw {
+            fn uri(&self) -> &str {
+                &self.uri
+            }
 
-    fn header<'a>(self: &'a Self) -> &'static str {
-        "STICKER"
-    }
+            fn kind(&self) -> AttachmentKind {
+                AttachmentKind::$kind
+            }
+        })*
+    };
 }
-impl HasURI for Video {
-    fn uri<'a>(self: &'a Self) -> &'a str {
-        &self.uri
-    }
 
-    fn header<'a>(self: &'a Self) -> &'static str {
-        "VIDEOS"
-    }
-}
-impl HasURI for Gif {
-    fn uri<'a>(self: &'a Self) -> &'a str {
-        &self.uri
-    }
+has_uri!(
+    Photo => Photo,
+    Gif => Gif,
+    Video => Video,
+    Sticker => Sticker,
+    AudioFile => Audio,
+    AttachedFile => File
+);
 
-    fn header<'a>(self: &'a Self) -> &'static str {
-        "GIFS"
-    }
+fn get_attachments<T: HasURI>(input: &[T]) -> Vec<Attachment> {
+    input
+        .iter()
+        .map(|v| Attachment {
+            kind: v.kind(),
+            uri: String::from(v.uri()),
+        })
+        .collect()
 }
-impl HasURI for Photo {
-    fn uri<'a>(self: &'a Self) -> &'a str {
-        &self.uri
-    }
 
-    fn header<'a>(self: &'a Self) -> &'static str {
-        "PHOTOS"
-    }
+/// Maps the path of every JSON file in the archive to its index
+pub fn get_json_names(archive: &Archive) -> Result<HashMap<String, usize>> {
+    Ok(archive
+        .file_names()
+        .iter()
+        .enumerate()
+        .filter_map(
+            |(i, name)| match Path::new(name).extension().and_then(OsStr::to_str) {
+                Some("json") => Some((String::from(name), i)),
+                _ => None,
+            },
+        )
+        .collect())
 }
 
-fn get_uris<T: HasURI>(input: &Vec<T>) -> String {
-    format!(
-        "{}: {}",
-        if input.len() > 0 {
-            input[0].header()
-        } else {
-            "NONE"
-        },
-        input
-            .iter()
-            .map(|v| (v.uri()))
-            .fold(String::new(), |a, b| format!("{}-{}", a, b))
-    )
-}
-
-pub fn get_names(
-    zip: &mut zip::read::ZipArchive<std::fs::File>,
-) -> std::io::Result<HashMap<String, usize>> {
-    Ok((0..zip.len())
-        .map(|i| {
-            let file = zip.by_index(i).unwrap();
-            match Path::new(file.name()).extension().and_then(OsStr::to_str) {
-                Some("json") => {
-                    if file.name().contains("_") {
-                        Some((String::from(file.name()), i))
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
-            }
-        })
-        .filter(|x| x.is_some())
-        .map(|x| x.unwrap())
+pub fn get_names(archive: &Archive) -> Result<HashMap<String, usize>> {
+    Ok(get_json_names(archive)?
+        .into_iter()
+        .filter(|(name, _)| name.contains('_'))
         .collect())
 }
 
@@ -159,7 +476,12 @@ fn is_control_character(c: u8) -> bool {
     c < 32 || c == 127
 }
 
-fn unfuck_facebook_unicode_escapes(json_data: &[u8]) -> String {
+// Also returns where, in the output, each run of escapes that had a broken one
+// in it starts, so the message holding it can be found
+fn unfuck_facebook_unicode_escapes(
+    json_data: &[u8],
+    mode: ParseMode,
+) -> Result<(String, Vec<usize>)> {
     // facebook doesn't encode unicode in JSON correctly -- they use
     // \u{UTF-8 sequence here} instead of just embedding the unicode
     // sequence or using a UTF codepoint. forgive me for this awful fsm
@@ -167,14 +489,18 @@ fn unfuck_facebook_unicode_escapes(json_data: &[u8]) -> String {
     // we also have to handle the terrible case of the escaped \u in the
     // json -- e.g \\urealdatahere. notably this isn't an issue if you have
     // \\\unicodehere -- that renders as \ unicode here
+
+    // in lenient mode, broken escapes become U+FFFD so that the rest of the
+    // file still parses, and the message holding them can be skipped on its own
     let mut prev_backslashes = 0;
 
-    let mut no_awful_unicode = Vec::with_capacity(json_data.len());
+    let mut no_awful_unicode = String::with_capacity(json_data.len());
+    let mut broken = Vec::new();
     let mut a = 0;
     while a < json_data.len() {
         // detect unicode code point
-        let mut cond =
-            (json_data[a] == b'\\' && json_data[a + 1] == b'u') && prev_backslashes % 2 == 0;
+        let mut cond = (json_data[a] == b'\\' && json_data.get(a + 1) == Some(&b'u'))
+            && prev_backslashes % 2 == 0;
         if !cond {
             if json_data[a] == b'\\' {
                 prev_backslashes += 1;
@@ -197,22 +523,51 @@ fn unfuck_facebook_unicode_escapes(json_data: &[u8]) -> String {
             // if we've discovered a unicode sequence, parse out each
             // utf-8 code unit
             let mut char_buf = Vec::new();
+            let mut is_broken = false;
             while cond {
                 // single code-unit, represented like \uXXXX,
                 // where XXXX is an 8-bit hex literal
-                let u8_buf = [
-                    json_data[a + 2],
-                    json_data[a + 3],
-                    json_data[a + 4],
-                    json_data[a + 5],
-                ];
-
-                if u8_buf.iter().any(|&c| is_control_character(c)) {
-                    break;
+                let u8_buf = json_data.get(a + 2..a + 6);
+
+                if let Some(u8_buf) = u8_buf {
+                    if u8_buf.iter().any(|&c| is_control_character(c)) {
+                        break;
+                    }
                 }
 
-                let u8_buf: &str = std::str::from_utf8(&u8_buf).unwrap();
-                let u8_elem: u8 = u8::from_str_radix(&u8_buf, 16).unwrap();
+                let u8_elem = u8_buf
+                    .and_then(|u8_buf| std::str::from_utf8(u8_buf).ok())
+                    .and_then(|u8_buf| u8::from_str_radix(u8_buf, 16).ok());
+                let u8_elem: u8 = match (u8_elem, mode) {
+                    (Some(u8_elem), _) => u8_elem,
+                    (None, ParseMode::Strict) => {
+                        return Err(Error::Encoding(match u8_buf {
+                            Some(u8_buf) => format!(
+                                "invalid \\u escape {:?} at byte {}",
+                                String::from_utf8_lossy(u8_buf),
+                                a
+                            ),
+                            None => format!("truncated \\u escape at byte {}", a),
+                        }))
+                    }
+                    (None, ParseMode::Lenient) => {
+                        // drop whatever hex digits the escape did have
+                        a += 2;
+                        let mut digits = 0;
+                        while digits < 4 && json_data.get(a).is_some_and(u8::is_ascii_hexdigit) {
+                            a += 1;
+                            digits += 1;
+                        }
+                        char_buf.extend_from_slice("\u{FFFD}".as_bytes());
+                        is_broken = true;
+
+                        if a >= json_data.len() {
+                            break;
+                        }
+                        cond = json_data[a] == b'\\' && json_data.get(a + 1) == Some(&b'u');
+                        continue;
+                    }
+                };
                 a += 6;
 
                 // Again, same check for Unicode control characters here --
@@ -225,222 +580,632 @@ fn unfuck_facebook_unicode_escapes(json_data: &[u8]) -> String {
                 if a >= json_data.len() {
                     break;
                 }
-                cond = json_data[a] == b'\\' && json_data[a + 1] == b'u';
+                cond = json_data[a] == b'\\' && json_data.get(a + 1) == Some(&b'u');
             }
 
-            let mut c_buf: Vec<char> = String::from_utf8(char_buf).unwrap().chars().collect();
+            let c_buf = match (String::from_utf8(char_buf), mode) {
+                (Ok(c_buf), _) => c_buf,
+                (Err(e), ParseMode::Strict) => return Err(e.into()),
+                (Err(e), ParseMode::Lenient) => {
+                    is_broken = true;
+                    String::from_utf8_lossy(e.as_bytes()).into_owned()
+                }
+            };
 
-            no_awful_unicode.append(&mut c_buf);
+            if is_broken {
+                broken.push(no_awful_unicode.len());
+            }
+            no_awful_unicode.push_str(&c_buf);
             prev_backslashes = 0;
         }
     }
-    let no_awful_unicode: String = no_awful_unicode.into_iter().collect();
 
-    no_awful_unicode
+    Ok((no_awful_unicode, broken))
 }
 
-// TODO: Create trait for message parsing, and move this into its own
-// impl
-pub fn parse_messages(
-    file: &mut zip::read::ZipFile,
-) -> serde_json::Result<(String, Vec<Participant>, Vec<Message>)> {
+fn to_message(v: &RawMessage) -> Result<Message> {
+    let mut attachments = Vec::new();
+    if let Some(photos) = &v.photos {
+        attachments.append(&mut get_attachments(photos));
+    }
+    if let Some(gifs) = &v.gifs {
+        attachments.append(&mut get_attachments(gifs));
+    }
+    if let Some(videos) = &v.videos {
+        attachments.append(&mut get_attachments(videos));
+    }
+    if let Some(audio_files) = &v.audio_files {
+        attachments.append(&mut get_attachments(audio_files));
+    }
+    if let Some(files) = &v.files {
+        attachments.append(&mut get_attachments(files));
+    }
+    if let Some(sticker) = &v.sticker {
+        attachments.append(&mut get_attachments(std::slice::from_ref(sticker)));
+    }
+
+    let users = || match &v.users {
+        Some(users) => users.iter().map(|user| user.name.clone()).collect(),
+        None => Vec::new(),
+    };
+    let content = v.content.as_deref().unwrap_or("");
+    // Only "Alice changed the theme", not someone saying they did
+    let has_phrase = |phrases: &[&str]| {
+        content
+            .strip_prefix(v.sender_name.as_str())
+            .and_then(|rest| rest.strip_prefix(' '))
+            .is_some_and(|rest| phrases.iter().any(|phrase| rest.starts_with(phrase)))
+    };
+
+    let kind = match v.r#type.as_str() {
+        // Missed calls have a duration of 0
+        "Call" => MessageKind::Call(v.call_duration.filter(|&duration| duration > 0)),
+        "Subscribe" => MessageKind::System(SystemEvent::Joined(users())),
+        "Unsubscribe" => MessageKind::System(SystemEvent::Left(users())),
+        _ if v.is_unsent => MessageKind::System(SystemEvent::Unsent),
+        "Generic" if has_phrase(UNSENT_PHRASES) => MessageKind::System(SystemEvent::Unsent),
+        "Generic" if has_phrase(NICKNAME_PHRASES) => MessageKind::System(SystemEvent::Nickname),
+        "Generic" if has_phrase(THEME_PHRASES) => MessageKind::System(SystemEvent::Theme),
+        _ => match (attachments.first(), &v.share, &v.story_share) {
+            (Some(attachment), _, _) => attachment.kind.message_kind(),
+            (None, Some(share), _) => MessageKind::Share(share.clone()),
+            (None, None, Some(story_share)) => MessageKind::Share(Share {
+                link: None,
+                share_text: Some(story_share.clone()),
+                original_content_owner: None,
+                expired_story: true,
+            }),
+            (None, None, None) => MessageKind::Text,
+        },
+    };
+
+    Ok(Message {
+        content: v.content.clone().unwrap_or_default(),
+        kind,
+        attachments,
+        reactions: v.reactions.clone().unwrap_or_default(),
+        id: None,
+        reply_to: v.replied_to.as_ref().map(|reply| match reply {
+            RawReply::Snippet(content) => ReplyTo::Snippet {
+                author: None,
+                content: content.clone(),
+                timestamp: None,
+            },
+            RawReply::Message {
+                sender_name,
+                content,
+                timestamp_ms,
+            } => ReplyTo::Snippet {
+                author: sender_name.clone(),
+                content: content.clone().unwrap_or_default(),
+                timestamp: timestamp_ms.and_then(|ms| Utc.timestamp_millis_opt(ms).single()),
+            },
+        }),
+        author: v.sender_name.clone(),
+        timestamp: Utc
+            .timestamp_millis_opt(v.timestamp_ms)
+            .single()
+            .ok_or_else(|| Error::Schema(format!("timestamp {} out of range", v.timestamp_ms)))?,
+    })
+}
+
+#[derive(Deserialize)]
+struct RawThread<'a> {
+    title: Option<String>,
+    participants: Vec<Participant>,
+    // Left undecoded, so that a single bad message can be skipped
+    #[serde(borrow)]
+    messages: Vec<&'a RawValue>,
+}
+
+/// One `message_N.json` file
+pub struct ParsedThread {
+    pub title: String,
+    pub participants: Vec<Participant>,
+    pub messages: Vec<Message>,
+    /// Index in the file of every message left out in lenient mode, and why
+    pub skipped: Vec<(usize, Error)>,
+}
+
+/// Parses one `message_N.json` file. In lenient mode, messages that can't be
+/// decoded are left out and listed in `skipped` instead
+pub fn parse_messages<R: Read + ?Sized>(file: &mut R, mode: ParseMode) -> Result<ParsedThread> {
     let mut u8_repr = Vec::new();
-    file.read_to_end(&mut u8_repr).unwrap();
+    file.read_to_end(&mut u8_repr)?;
 
-    let mut no_awful_unicode = unfuck_facebook_unicode_escapes(&u8_repr);
+    let (no_awful_unicode, broken) = unfuck_facebook_unicode_escapes(&u8_repr, mode)?;
 
-    let file: serde_json::Value = match serde_json::from_str(&mut no_awful_unicode) {
-        Ok(file) => file,
-        Err(e) => {
-            let mut test_f = File::create("/tmp/coraline_log.json").unwrap();
-            test_f.write_all(no_awful_unicode.as_bytes()).unwrap();
-            panic!("Failed on {:?} -- {:?}", no_awful_unicode, e)
-        }
+    let file: RawThread = serde_json::from_str(&no_awful_unicode)?;
+    let title = match file.title {
+        Some(title) => title,
+        None => return Err(Error::Schema(String::from("thread has no title"))),
     };
-    let title: String = serde_json::from_value(file["title"].clone()).unwrap();
 
-    let participants: Vec<Participant> = serde_json::from_value(file["participants"].clone())?;
-    let messages: Vec<RawMessage> = serde_json::from_value(file["messages"].clone())?;
+    let mut messages = Vec::with_capacity(file.messages.len());
+    let mut skipped = Vec::new();
+    let mut broken_in_messages = 0;
+    for (i, raw_message) in file.messages.into_iter().enumerate() {
+        // Where the message is in the unfucked file, to find any broken
+        // escapes in it
+        let start = raw_message.get().as_ptr() as usize - no_awful_unicode.as_ptr() as usize;
+        let end = start + raw_message.get().len();
+        let first = broken.partition_point(|&offset| offset < start);
+        let count = broken[first..].partition_point(|&offset| offset < end);
+        broken_in_messages += count;
+
+        let message = if count > 0 {
+            Err(Error::Encoding(String::from(
+                "invalid \\u escape in message",
+            )))
+        } else {
+            serde_json::from_str(raw_message.get())
+                .map_err(Error::from)
+                .and_then(|raw_message| to_message(&raw_message))
+        };
+        match (message, mode) {
+            (Ok(message), _) => messages.push(message),
+            (Err(e), ParseMode::Strict) => return Err(e),
+            (Err(e), ParseMode::Lenient) => skipped.push((i, e)),
+        }
+    }
 
-    let messages: Vec<Message> = messages
+    // The title and participants can't be left out like a message can
+    if broken_in_messages < broken.len() {
+        return Err(Error::Encoding(String::from(
+            "invalid \\u escape in thread title or participants",
+        )));
+    }
+
+    Ok(ParsedThread {
+        title,
+        participants: file.participants,
+        messages,
+        skipped,
+    })
+}
+
+// Snippets are cut short, sometimes with an ellipsis
+fn matches_snippet(
+    message: &Message,
+    author: &Option<String>,
+    content: &str,
+    timestamp: &Option<DateTime<Utc>>,
+) -> bool {
+    let content = content.trim_end_matches('…').trim_end_matches("...");
+    author
+        .as_ref()
+        .is_none_or(|author| &message.author == author)
+        && timestamp.is_none_or(|timestamp| message.timestamp == timestamp)
+        && message.content.starts_with(content)
+}
+
+/// Looks up the message that every reply in a sorted conversation replies
+/// to. Replies to messages that aren't in the conversation are left as they
+/// are, so this can be called again once more of it has been read
+pub fn resolve_replies(messages: &mut [Message]) {
+    let ids: HashMap<String, usize> = messages
         .iter()
-        .map(|v: &RawMessage| Message {
-            author: v.sender_name.clone(),
-            timestamp: DateTime::from_utc(
-                NaiveDateTime::from_timestamp(
-                    v.timestamp_ms / 1000,
-                    (v.timestamp_ms % 1000) as u32 * 1000,
-                ),
-                Utc,
-            ),
-            content: match &v.content {
-                Some(content) => content.clone(),
-                None => {
-                    // awful hack
-                    match &v.photos {
-                        Some(photos) => get_uris(&photos),
-                        None => match &v.gifs {
-                            Some(gifs) => get_uris(&gifs),
-                            None => match &v.videos {
-                                Some(videos) => get_uris(&videos),
-                                None => String::from(match &v.sticker {
-                                    Some(sticker) => sticker.uri(),
-                                    None => "UNKOWN CONTENT TYPE",
-                                }),
-                            },
-                        },
-                    }
-                }
-            },
-        })
+        .enumerate()
+        .filter_map(|(i, message)| Some((message.id.clone()?, i)))
         .collect();
 
-    Ok((title, participants, messages))
+    for i in 0..messages.len() {
+        let quoted = match &messages[i].reply_to {
+            Some(ReplyTo::Id(id)) => ids.get(id).copied(),
+            Some(ReplyTo::Snippet {
+                author,
+                content,
+                timestamp,
+            }) => (0..i)
+                .rev()
+                .find(|&j| matches_snippet(&messages[j], author, content, timestamp)),
+            _ => None,
+        };
+
+        if let Some(j) = quoted {
+            messages[i].reply_to = Some(ReplyTo::Message {
+                author: messages[j].author.clone(),
+                content: messages[j].render_content(),
+                timestamp: messages[j].timestamp,
+            });
+        }
+    }
+}
+
+/// Merges bursts of text messages by the same author into one turn, as long
+/// as each came within `max_gap` of the one before. Contents are joined with
+/// `joiner`, and the turn keeps the last message's timestamp so the gaps a
+/// `Segmenter` sees don't change. `conversation` must be sorted
+pub fn merge_turns(conversation: &[Message], max_gap: Duration, joiner: &str) -> Vec<Message> {
+    let mut turns: Vec<Message> = Vec::new();
+    for message in conversation {
+        match turns.last_mut() {
+            Some(turn)
+                if turn.author == message.author
+                    && turn.kind == MessageKind::Text
+                    && message.kind == MessageKind::Text
+                    && message.timestamp.signed_duration_since(turn.timestamp) <= max_gap =>
+            {
+                turn.content.push_str(joiner);
+                turn.content.push_str(&message.content);
+                turn.reactions.extend(message.reactions.iter().cloned());
+                turn.timestamp = message.timestamp;
+                if turn.reply_to.is_none() {
+                    turn.reply_to = message.reply_to.clone();
+                }
+            }
+            _ => turns.push(message.clone()),
+        }
+    }
+    turns
 }
 
+/// Splits a sorted conversation into training and test sets, a segment of
+/// `segmenter` at a time. Only segments starting more than `min_gap_days`
+/// whole days after the last move can move to the other set, so
+/// conversations that carry on from each other stay together
 pub fn train_test(
     conversation: &[Message],
     ratio: f32,
     rng: &mut Pcg64Mcg,
+    segmenter: &Segmenter,
+    min_gap_days: i64,
 ) -> (Vec<Message>, Vec<Message>) {
     let mut train_msgs: Vec<_> = Vec::new();
     let mut test_msgs: Vec<_> = Vec::new();
     let mut is_train: bool = true;
-    let mut conversation_timestamp: DateTime<Utc> = conversation[0].timestamp;
-    let mut last_timestamp: DateTime<Utc> = conversation[0].timestamp;
+    let mut conversation_timestamp: Option<DateTime<Utc>> = None;
 
-    for message in conversation {
-        let last_diff: Duration = message.timestamp.signed_duration_since(last_timestamp);
-        let convo_diff: Duration = message
-            .timestamp
-            .signed_duration_since(conversation_timestamp);
-
-        // If it's been a while, consider moving a conversation
-        // to the other set
-        if last_diff.num_seconds() > CONVERSATION_TIMEOUT
-            && convo_diff.num_days() > TRAIN_TEST_TIMEOUT
-        {
-            is_train = rng.gen::<f32>() > ratio;
-            conversation_timestamp = message.timestamp;
+    for segment in segmenter.split(conversation) {
+        let start = segment[0].timestamp;
+        match conversation_timestamp {
+            // If it's been a while, consider moving a conversation
+            // to the other set
+            Some(timestamp) if start.signed_duration_since(timestamp).num_days() > min_gap_days => {
+                is_train = rng.gen::<f32>() > ratio;
+                conversation_timestamp = Some(start);
+            }
+            Some(_) => {}
+            None => conversation_timestamp = Some(start),
         }
-        last_timestamp = message.timestamp;
 
         if is_train {
-            train_msgs.push(message.clone());
+            train_msgs.extend_from_slice(segment);
         } else {
-            test_msgs.push(message.clone());
+            test_msgs.extend_from_slice(segment);
         }
     }
 
     (train_msgs, test_msgs)
 }
 
-pub fn format_conversation(
-    conversation: &[Message],
-    participants: &[Participant],
-    eom: &str,
-    eoc: &str,
-) -> String {
-    if conversation.len() == 0 {
-        return String::new();
-    }
-
-    let mut all_message_strs: Vec<String> = Vec::new();
-    let mut current_conversation_strs: Vec<String> = Vec::new();
-    let mut conversation_timestamp: DateTime<Utc> = conversation[0].timestamp;
-    let mut last_timestamp: DateTime<Utc> = conversation[0].timestamp;
-
-    // Let's remind GPT-3 at the start of each conversation
-    let header = format!(
-        "|Participants: {:?}|\n",
-        participants
+/// Groups the files that `include` accepts by the name of the directory
+/// they're in
+pub fn group_by_directory<F: Fn(&str) -> bool>(
+    names: &HashMap<String, usize>,
+    include: F,
+) -> MultiMap<String, usize> {
+    names
+        .iter()
+        .filter(|(name, _)| include(name))
+        .filter_map(|(name, idx)| {
+            let directory = Path::new(&name).parent()?.file_name()?.to_str()?;
+            Some((String::from(directory), *idx))
+        })
+        .collect()
+}
+
+/// Threads live at `messages/inbox/<thread>/message_N.json` (or under
+/// `archived_threads/` and friends). That's the root of older Facebook
+/// exports, but newer Facebook and Instagram ones nest it a directory deeper,
+/// e.g. `your_instagram_activity/messages/inbox/...`
+pub fn is_thread_file(name: &str) -> bool {
+    Path::new(name).ancestors().nth(3).and_then(Path::file_name) == Some(OsStr::new("messages"))
+}
+
+pub fn get_all_conversations(archive: &Archive) -> Result<MultiMap<String, usize>> {
+    Ok(group_by_directory(&get_names(archive)?, is_thread_file))
+}
+
+pub fn list(source: &dyn ChatSource) -> Vec<String> {
+    source.conversation_names()
+}
+
+/// Opens a chat export, picking the right `ChatSource` from its contents.
+/// Exports can be zipped or already unpacked into a directory
+pub fn open_source(path: &str, mode: ParseMode) -> Result<Box<dyn ChatSource>> {
+    match Path::new(path).extension().and_then(OsStr::to_str) {
+        Some("txt") => return Ok(Box::new(whatsapp::WhatsAppSource::open(path)?)),
+        Some("json") => {
+            let mut json_data = String::new();
+            File::open(path)?.read_to_string(&mut json_data)?;
+
+            if discord::is_discord_export(&json_data) {
+                let mut source = discord::DiscordSource::new();
+                source.add_channel(&json_data)?;
+                return Ok(Box::new(source));
+            }
+            if telegram::is_telegram_export(&json_data) {
+                return Ok(Box::new(telegram::TelegramSource::from_json(&json_data)?));
+            }
+            return Err(Error::Schema(format!(
+                "{} isn't a Discord or Telegram export",
+                path
+            )));
+        }
+        _ => {}
+    }
+
+    let mut archive = Archive::open(path)?;
+    Ok(if slack::is_slack_archive(&archive) {
+        Box::new(slack::SlackSource::new(archive)?)
+    } else if telegram::is_telegram_archive(&archive) {
+        Box::new(telegram::TelegramSource::from_archive(&mut archive)?)
+    } else if whatsapp::is_whatsapp_archive(&archive) {
+        let stem = Path::new(path)
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .unwrap_or("chat");
+        Box::new(whatsapp::WhatsAppSource::from_archive(&mut archive, stem)?)
+    } else if get_all_conversations(&archive)?.is_empty() {
+        // DiscordChatExporter writes one file per channel, so a directory or
+        // zip of them is a whole server
+        Box::new(discord::DiscordSource::from_archive(&mut archive)?)
+    } else {
+        Box::new(FacebookSource::new(archive)?.with_mode(mode))
+    })
+}
+
+/// Expands any glob patterns (e.g. `facebook-user-*.zip`) among the inputs.
+/// Inputs that aren't patterns are passed through untouched. A pattern that
+/// matches nothing is an error, as it's almost always a typo
+pub fn expand_inputs(inputs: &[&str]) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for input in inputs {
+        if input.contains(['*', '?', '[']) {
+            let mut matches: Vec<String> = glob::glob(input)?
+                .map(|path| Ok(path?.to_string_lossy().into_owned()))
+                .collect::<Result<Vec<String>>>()?;
+            if matches.is_empty() {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{} matches no files", input),
+                )));
+            }
+            matches.sort();
+            paths.append(&mut matches);
+        } else {
+            paths.push(String::from(*input));
+        }
+    }
+    Ok(paths)
+}
+
+/// Opens one or more chat exports as a single `ChatSource`
+pub fn open_sources(paths: &[String], mode: ParseMode) -> Result<Box<dyn ChatSource>> {
+    match paths {
+        [] => Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no inputs to read",
+        ))),
+        [path] => open_source(path, mode),
+        _ => Ok(Box::new(MultiSource::new(
+            paths
+                .iter()
+                .map(|path| open_source(path, mode))
+                .collect::<Result<Vec<_>>>()?,
+        ))),
+    }
+}
+
+/// Several exports read as one -- mostly for large Facebook downloads, which
+/// arrive as `facebook-user-1.zip`, `facebook-user-2.zip`... with the
+/// `message_N.json` files of one thread spread across the parts. Each
+/// conversation is merged from every export that has part of it
+pub struct MultiSource {
+    sources: Vec<Box<dyn ChatSource>>,
+}
+
+impl MultiSource {
+    pub fn new(sources: Vec<Box<dyn ChatSource>>) -> MultiSource {
+        MultiSource { sources }
+    }
+}
+
+impl ChatSource for MultiSource {
+    fn conversation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .sources
             .iter()
-            .map(|p| p.name.clone())
-            .collect::<Vec<String>>()
-            .join(", ")
-    );
+            .flat_map(|source| source.conversation_names())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
 
-    for message in conversation {
-        let diff: Duration = message.timestamp.signed_duration_since(last_timestamp);
-        if diff.num_seconds() > CONVERSATION_TIMEOUT {
-            conversation_timestamp = message.timestamp;
+    fn read_conversation(&mut self, name: &str) -> Result<Option<Conversation>> {
+        let mut merged: Option<Conversation> = None;
+        for source in self.sources.iter_mut() {
+            let mut conversation = match source.read_conversation(name)? {
+                Some(conversation) => conversation,
+                None => continue,
+            };
 
-            all_message_strs.push(current_conversation_strs.join(eom));
-            current_conversation_strs.clear();
-            current_conversation_strs.push(header.clone());
+            match merged.as_mut() {
+                None => merged = Some(conversation),
+                Some(merged) => {
+                    for participant in conversation.participants {
+                        if !merged.participants.contains(&participant) {
+                            merged.participants.push(participant);
+                        }
+                    }
+                    merged.messages.append(&mut conversation.messages);
+                }
+            }
         }
-        let diff: Duration = message
-            .timestamp
-            .signed_duration_since(conversation_timestamp);
-
-        let formatted_msg = format!(
-            "|{} {} {} {}|: {}\n",
-            conversation_timestamp.month(),
-            conversation_timestamp.year(),
-            diff.num_seconds(),
-            message.author,
-            message.content
+
+        let mut merged = match merged {
+            Some(merged) => merged,
+            None => return Ok(None),
+        };
+        merged.messages.sort_by_key(|a| a.timestamp);
+        // Replies to messages in another part of the export
+        resolve_replies(&mut merged.messages);
+        println!(
+            "Merged {} messages from {} exports",
+            merged.messages.len(),
+            self.sources.len()
         );
 
-        current_conversation_strs.push(formatted_msg);
+        Ok(Some(merged))
+    }
 
-        last_timestamp = message.timestamp;
+    fn take_skipped(&mut self) -> Vec<SkippedMessage> {
+        self.sources
+            .iter_mut()
+            .flat_map(|source| source.take_skipped())
+            .collect()
     }
+}
 
-    return all_message_strs.join(eoc);
+/// Facebook Messenger or Instagram JSON export, either the downloaded zip or
+/// a directory it was unpacked into. Both use the same message schema
+pub struct FacebookSource {
+    archive: Archive,
+    // Maps from a String of the conversation name -> all conversation file IDs
+    conversations: MultiMap<String, usize>,
+    mode: ParseMode,
+    skipped: Vec<SkippedMessage>,
 }
 
-pub fn get_all_conversations(zip: &mut zip::ZipArchive<File>) -> MultiMap<String, usize> {
-    get_names(zip)
-        .unwrap()
-        .iter()
-        .filter(|(name, _)| Path::new(&name).components().count() == 4)
-        .map(|(name, idx)| {
-            (
-                String::from(
-                    Path::new(&name)
-                        .parent()
-                        .unwrap()
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap(),
-                ),
-                *idx,
-            )
+impl FacebookSource {
+    pub fn new(archive: Archive) -> Result<FacebookSource> {
+        let conversations = get_all_conversations(&archive)?;
+        Ok(FacebookSource {
+            archive,
+            conversations,
+            mode: ParseMode::default(),
+            skipped: Vec::new(),
         })
-        .collect()
+    }
+
+    pub fn open(fb_file: &str) -> Result<FacebookSource> {
+        FacebookSource::new(Archive::open(fb_file)?)
+    }
+
+    pub fn with_mode(mut self, mode: ParseMode) -> FacebookSource {
+        self.mode = mode;
+        self
+    }
 }
 
-pub fn list(fb_file: &str) -> Vec<String> {
-    let zip_file = File::open(fb_file).unwrap();
-    let mut zip = zip::ZipArchive::new(zip_file).unwrap();
+impl ChatSource for FacebookSource {
+    fn conversation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.conversations.keys().cloned().collect();
+        names.sort();
+        names
+    }
 
-    let all_conversations: MultiMap<String, usize> = get_all_conversations(&mut zip);
-    let all_conversations: Vec<(String, Vec<usize>)> = all_conversations.into_iter().collect();
-    println!("{:?}", all_conversations);
-    let all_conversations: Vec<String> = all_conversations
-        .iter()
-        .map(|(name, _)| name.clone())
-        .collect();
+    fn read_conversation(&mut self, name: &str) -> Result<Option<Conversation>> {
+        let conversation_idx = match self.conversations.get_vec(name) {
+            Some(conversation_idx) => conversation_idx,
+            None => return Ok(None),
+        };
+
+        let mut title = None;
+        let mut participants: Vec<Participant> = Vec::new();
+        let mut conversation_messages: Vec<Message> = Vec::new();
+
+        for (i, &idx) in conversation_idx.iter().enumerate() {
+            let mut thread = parse_messages(&mut *self.archive.by_index(idx)?, self.mode)?;
+            let path = &self.archive.file_names()[idx];
+            self.skipped.extend(
+                thread
+                    .skipped
+                    .drain(..)
+                    .map(|(index, error)| SkippedMessage {
+                        path: path.clone(),
+                        index,
+                        error,
+                    }),
+            );
+
+            if thread.messages.is_empty() {
+                continue;
+            }
+
+            // Each file lists whoever was in the chat when it was written, so
+            // people who joined or left partway through are only in some
+            for participant in thread.participants {
+                if !participants.contains(&participant) {
+                    participants.push(participant);
+                }
+            }
+            title = Some(thread.title);
+            println!(
+                "Parsed {} messages from json file {} -- {:.2} MB",
+                &thread.messages.len(),
+                i,
+                (self.archive.size(idx)? as f64) / (1 << 20) as f64
+            );
+
+            conversation_messages.append(&mut thread.messages);
+        }
 
-    all_conversations
+        conversation_messages.sort_by_key(|a| a.timestamp);
+        resolve_replies(&mut conversation_messages);
+        println!(
+            "Sorted {} messages by timestamp",
+            conversation_messages.len()
+        );
+
+        Ok(title.map(|title| Conversation {
+            name: String::from(name),
+            title,
+            participants,
+            messages: conversation_messages,
+        }))
+    }
+
+    fn take_skipped(&mut self) -> Vec<SkippedMessage> {
+        std::mem::take(&mut self.skipped)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+
     #[test]
     fn test_unfuck_facebook_unicode_escapes_basic() {
         let input = b"asdf";
-        assert_eq!(unfuck_facebook_unicode_escapes(input), "asdf");
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
+            "asdf"
+        );
         let input = b"\\u0041A";
-        assert_eq!(unfuck_facebook_unicode_escapes(input), "AA");
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
+            "AA"
+        );
         let input = b"Rados\\u00c5\\u0082aw";
-        assert_eq!(unfuck_facebook_unicode_escapes(input), "Radosław");
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
+            "Radosław"
+        );
         let input = b"No to trzeba ostatnie treningi zrobi\\u00c4\\u0087 xD";
         assert_eq!(
-            unfuck_facebook_unicode_escapes(input),
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
             "No to trzeba ostatnie treningi zrobić xD"
         );
     }
@@ -448,15 +1213,567 @@ mod tests {
     #[test]
     fn test_unfuck_facebook_unicode_escapes_boundaries() {
         let input = b"\\u0041";
-        assert_eq!(unfuck_facebook_unicode_escapes(input), "A");
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
+            "A"
+        );
 
         let input = b"\\\\u0041A";
-        assert_eq!(unfuck_facebook_unicode_escapes(input), "\\\\u0041A");
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
+            "\\\\u0041A"
+        );
+    }
+
+    #[test]
+    fn test_is_thread_file() {
+        assert!(is_thread_file("messages/inbox/alice_abc123/message_1.json"));
+        assert!(is_thread_file(
+            "your_instagram_activity/messages/inbox/bob_123/message_2.json"
+        ));
+        assert!(is_thread_file(
+            "messages/archived_threads/carol_42/message_1.json"
+        ));
+        assert!(!is_thread_file("messages/autofill_information.json"));
+        assert!(!is_thread_file(
+            "your_instagram_activity/messages/secret_conversations.json"
+        ));
+    }
+
+    // Writes the parts of a thread into an unpacked Facebook export
+    fn write_export(root: &Path, files: &[(&str, &str)]) {
+        let thread = root.join("messages").join("inbox").join("alice_abc123");
+        std::fs::create_dir_all(&thread).unwrap();
+        for (name, json_data) in files {
+            std::fs::write(thread.join(name), json_data).unwrap();
+        }
+    }
+
+    fn write_zip(path: &Path, files: &[(&str, &str)]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_open_source_discord() {
+        let channel = |id: &str, name: &str| {
+            format!(
+                r#"{{"guild": {{"id": "1", "name": "Coraline"}},
+                    "channel": {{"id": "{}", "type": "GuildTextChat", "name": "{}"}},
+                    "messages": [{{"id": "100", "type": "Default",
+                        "timestamp": "2021-01-01T00:00:00+00:00", "content": "hello",
+                        "author": {{"id": "5", "name": "alice"}}}}]}}"#,
+                id, name
+            )
+        };
+        let (general, random) = (channel("22", "general"), channel("23", "random"));
+        let files = [
+            ("general.json", general.as_str()),
+            ("random.json", random.as_str()),
+        ];
+
+        let root =
+            std::env::temp_dir().join(format!("chat_log_parser_discord_{}", std::process::id()));
+        let dir = root.join("server");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, json_data) in files.iter() {
+            std::fs::write(dir.join(name), json_data).unwrap();
+        }
+        let zip_path = root.join("server.zip");
+        write_zip(&zip_path, &files);
+
+        for path in [dir, zip_path].iter() {
+            let source = open_source(path.to_str().unwrap(), ParseMode::Strict).unwrap();
+            assert_eq!(
+                source.conversation_names(),
+                vec!["coraline_general_22", "coraline_random_23"]
+            );
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_directory_and_zip() {
+        let thread = r#"{
+            "participants": [{"name": "Alice"}, {"name": "Bob"}],
+            "messages": [
+                {"sender_name": "Bob", "timestamp_ms": 1609459201000, "content": "caf\u00c3\u00a9?"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459200000, "content": "hi"}
+            ],
+            "title": "Alice"
+        }"#;
+        let root =
+            std::env::temp_dir().join(format!("chat_log_parser_unpacked_{}", std::process::id()));
+        write_export(&root.join("export"), &[("message_1.json", thread)]);
+        let zip_path = root.join("export.zip");
+        write_zip(
+            &zip_path,
+            &[("messages/inbox/alice_abc123/message_1.json", thread)],
+        );
+
+        // Everything but the messages themselves can be compared directly
+        let read = |path: &Path| {
+            let mut source = open_source(path.to_str().unwrap(), ParseMode::Strict).unwrap();
+            let names = source.conversation_names();
+            let conversation = source.read_conversation(&names[0]).unwrap().unwrap();
+            let messages: Vec<(String, String, DateTime<Utc>)> = conversation
+                .messages
+                .iter()
+                .map(|message| {
+                    (
+                        message.author.clone(),
+                        message.content.clone(),
+                        message.timestamp,
+                    )
+                })
+                .collect();
+            (
+                names,
+                conversation.title,
+                conversation.participants,
+                messages,
+            )
+        };
+        let unpacked = read(&root.join("export"));
+        assert_eq!(unpacked, read(&zip_path));
+        assert_eq!(unpacked.0, vec!["alice_abc123"]);
+        assert_eq!(unpacked.3[1].1, "café?");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_multi_source() {
+        // One thread whose files ended up in two separate downloads
+        let root =
+            std::env::temp_dir().join(format!("chat_log_parser_multi_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let paths = [root.join("part_1.zip"), root.join("part_2.zip")];
+        write_zip(
+            &paths[0],
+            &[(
+                "messages/inbox/alice_abc123/message_1.json",
+                r#"{
+                    "participants": [{"name": "Alice"}, {"name": "Bob"}],
+                    "messages": [
+                        {"sender_name": "Bob", "timestamp_ms": 1609459203000, "content": "c"},
+                        {"sender_name": "Alice", "timestamp_ms": 1609459200000, "content": "a"}
+                    ],
+                    "title": "Alice"
+                }"#,
+            )],
+        );
+        write_zip(
+            &paths[1],
+            &[(
+                "messages/inbox/alice_abc123/message_2.json",
+                r#"{
+                    "participants": [{"name": "Alice"}, {"name": "Carol"}],
+                    "messages": [
+                        {"sender_name": "Carol", "timestamp_ms": 1609459204000, "content": "d"},
+                        {"sender_name": "Alice", "timestamp_ms": 1609459201000, "content": "b"}
+                    ],
+                    "title": "Alice"
+                }"#,
+            )],
+        );
+
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        let mut source = open_sources(&paths, ParseMode::Strict).unwrap();
+        assert_eq!(
+            source.conversation_names(),
+            vec![String::from("alice_abc123")]
+        );
+
+        let conversation = source.read_conversation("alice_abc123").unwrap().unwrap();
+        let contents: Vec<&str> = conversation
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["a", "b", "c", "d"]);
+        let mut names: Vec<&str> = conversation
+            .participants
+            .iter()
+            .map(|participant| participant.name.as_str())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["Alice", "Bob", "Carol"]);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let root =
+            std::env::temp_dir().join(format!("chat_log_parser_glob_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        for name in ["part_2.zip", "part_1.zip", "other.zip"].iter() {
+            std::fs::write(root.join(name), "").unwrap();
+        }
+
+        let pattern = root.join("part_*.zip");
+        let exact = root.join("other.zip");
+        let paths = expand_inputs(&[pattern.to_str().unwrap(), exact.to_str().unwrap()]).unwrap();
+        let names: Vec<&str> = paths
+            .iter()
+            .map(|path| path.rsplit(std::path::MAIN_SEPARATOR).next().unwrap())
+            .collect();
+        assert_eq!(names, vec!["part_1.zip", "part_2.zip", "other.zip"]);
+
+        let missing = root.join("nothing_*");
+        assert!(matches!(
+            expand_inputs(&[missing.to_str().unwrap()]),
+            Err(Error::Io(_))
+        ));
+        assert!(open_sources(&[], ParseMode::Strict).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
     #[test]
     fn test_unfuck_facebook_control_characters() {
         let input = b"\\u0013";
-        assert_eq!(unfuck_facebook_unicode_escapes(input), "");
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
+            ""
+        );
+    }
+
+    #[test]
+    fn test_unfuck_facebook_malformed_escapes() {
+        let input = b"abc\\u00c";
+        assert!(matches!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict),
+            Err(Error::Encoding(_))
+        ));
+        let input = b"\\u00zz";
+        assert!(matches!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict),
+            Err(Error::Encoding(_))
+        ));
+        let input = b"\\u00c5";
+        assert!(matches!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict),
+            Err(Error::Encoding(_))
+        ));
+        let input = b"trailing \\";
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Strict)
+                .unwrap()
+                .0,
+            "trailing \\"
+        );
+    }
+
+    #[test]
+    fn test_unfuck_facebook_lenient_escapes() {
+        let input = b"\"abc\\u00c\"";
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Lenient).unwrap(),
+            (String::from("\"abc\u{FFFD}\""), vec![4])
+        );
+        let input = b"\\u00c5 Rados\\u00c5\\u0082aw";
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Lenient).unwrap(),
+            (String::from("\u{FFFD} Radosław"), vec![0])
+        );
+        // A real U+FFFD isn't a broken escape
+        let input = b"\\u00ef\\u00bf\\u00bd";
+        assert_eq!(
+            unfuck_facebook_unicode_escapes(input, ParseMode::Lenient).unwrap(),
+            (String::from("\u{FFFD}"), vec![])
+        );
+    }
+
+    #[test]
+    fn test_parse_messages_content_kinds() {
+        let thread = br#"{
+            "participants": [{"name": "Alice"}, {"name": "Bob"}],
+            "messages": [
+                {"sender_name": "Alice", "timestamp_ms": 1609459206000,
+                 "content": "Alice called you.", "call_duration": 93, "type": "Call"},
+                {"sender_name": "Bob", "timestamp_ms": 1609459205000,
+                 "audio_files": [{"uri": "audio/a.mp4", "creation_timestamp": 1}], "type": "Generic"},
+                {"sender_name": "Bob", "timestamp_ms": 1609459204000,
+                 "sticker": {"uri": "stickers/s.png"}, "type": "Generic"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459203000,
+                 "files": [{"uri": "files/doc.pdf", "creation_timestamp": 1}], "type": "Generic"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459202000, "content": "nice",
+                 "story_share": "Story unavailable"},
+                {"sender_name": "Bob", "timestamp_ms": 1609459201000, "content": "look",
+                 "share": {"link": "https://example.com"}, "type": "Share",
+                 "reactions": [{"reaction": "\u00e2\u009d\u00a4", "actor": "Alice"}]}
+            ],
+            "title": "Alice"
+        }"#;
+        let messages = parse_messages(&mut &thread[..], ParseMode::Strict)
+            .unwrap()
+            .messages;
+
+        assert_eq!(messages[0].kind, MessageKind::Call(Some(93)));
+        assert_eq!(messages[0].render_content(), "Alice called you.");
+        assert_eq!(messages[1].kind, MessageKind::Audio);
+        assert_eq!(messages[1].render_content(), "AUDIO: -audio/a.mp4");
+        assert_eq!(messages[2].kind, MessageKind::Sticker);
+        assert_eq!(messages[2].render_content(), "STICKER: -stickers/s.png");
+        assert_eq!(messages[3].kind, MessageKind::File);
+        assert_eq!(messages[3].attachments[0].uri, "files/doc.pdf");
+        assert_eq!(
+            messages[4].render_content(),
+            "STORY: -Story unavailable nice"
+        );
+        assert!(matches!(messages[5].kind, MessageKind::Share(_)));
+        assert_eq!(messages[5].render_content(), "look");
+        assert_eq!(
+            messages[5].reactions,
+            vec![Reaction {
+                reaction: String::from("❤"),
+                actor: String::from("Alice"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_merge_turns() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let mut photo = Message::text("Alice", "", start + Duration::seconds(3));
+        photo.kind = MessageKind::Photo;
+        let conversation = vec![
+            Message::text("Alice", "ok", start),
+            Message::text("Alice", "wait", start + Duration::seconds(1)),
+            Message::text("Alice", "so basically", start + Duration::seconds(2)),
+            photo,
+            Message::text("Bob", "go on", start + Duration::seconds(4)),
+            Message::text("Bob", "hello?", start + Duration::seconds(300)),
+        ];
+
+        let turns = merge_turns(&conversation, Duration::seconds(60), " / ");
+        let contents: Vec<&str> = turns.iter().map(|turn| turn.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec!["ok / wait / so basically", "", "go on", "hello?"]
+        );
+        assert_eq!(turns[0].timestamp, start + Duration::seconds(2));
+    }
+
+    #[test]
+    fn test_train_test_gap() {
+        use rand::SeedableRng;
+
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let conversation = vec![
+            Message::text("Alice", "hi", start),
+            Message::text("Bob", "hey", start + Duration::days(3)),
+        ];
+        // With a ratio of 1 every move goes to the test set
+        let split = |min_gap_days| {
+            let mut rng = Pcg64Mcg::seed_from_u64(0);
+            let (train, test) = train_test(
+                &conversation,
+                1.0,
+                &mut rng,
+                &Segmenter::default(),
+                min_gap_days,
+            );
+            (train.len(), test.len())
+        };
+        assert_eq!(split(1), (1, 1));
+        assert_eq!(split(3), (2, 0));
+    }
+
+    #[test]
+    fn test_replies() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let mut reply = Message::text("Bob", "who's there", start + Duration::seconds(9));
+        reply.reply_to = Some(ReplyTo::Snippet {
+            author: Some(String::from("Alice")),
+            content: String::from("knock kn…"),
+            timestamp: None,
+        });
+        let mut conversation = vec![
+            Message::text("Alice", "knock knock", start),
+            Message::text("Alice", "anyone?", start + Duration::seconds(5)),
+            reply,
+            Message::text("Alice", "later", start + Duration::hours(1)),
+        ];
+        resolve_replies(&mut conversation);
+        assert_eq!(
+            conversation[2].reply_to,
+            Some(ReplyTo::Message {
+                author: String::from("Alice"),
+                content: String::from("knock knock"),
+                timestamp: start,
+            })
+        );
+
+        let format = |replies| {
+            let options = FormatOptions {
+                replies,
+                ..FormatOptions::default()
+            };
+            format_conversation(&conversation, &[], "|EOM|", "<|endoftext|>", &options)
+        };
+        assert!(format(ReplyStyle::Inline)
+            .contains("|1 2021 9 Bob|: |Replying to Alice: knock knock| who's there\n"));
+        assert_eq!(
+            format(ReplyStyle::Pairs),
+            "|1 2021 0 Alice|: knock knock\n|EOM||1 2021 9 Bob|: who's there\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_messages_system_events() {
+        let thread = br#"{
+            "participants": [{"name": "Alice"}, {"name": "Bob"}],
+            "messages": [
+                {"sender_name": "Alice", "timestamp_ms": 1609459209000,
+                 "content": "Alice unsent a message", "type": "Generic"},
+                {"sender_name": "Bob", "timestamp_ms": 1609459208000,
+                 "content": "why did you say you unsent a message", "type": "Generic"},
+                {"sender_name": "Bob", "timestamp_ms": 1609459207000,
+                 "content": "lol she named the group after her cat", "type": "Generic"},
+                {"sender_name": "Bob", "timestamp_ms": 1609459206000,
+                 "content": "Alice changed the theme to Love.", "type": "Generic"},
+                {"sender_name": "Bob", "timestamp_ms": 1609459205000, "is_unsent": true,
+                 "type": "Generic"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459204000,
+                 "content": "Alice set the nickname for Bob to Bobby.", "type": "Generic"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459203000,
+                 "content": "Alice changed the theme to Love.", "type": "Generic"},
+                {"sender_name": "Carol", "timestamp_ms": 1609459202000,
+                 "content": "Carol left the group.", "users": [{"name": "Carol"}],
+                 "type": "Unsubscribe"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459201000,
+                 "content": "Alice added Carol to the group.", "users": [{"name": "Carol"}],
+                 "type": "Subscribe"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459200000,
+                 "content": "The video chat ended.", "call_duration": 0, "type": "Call"}
+            ],
+            "title": "Alice"
+        }"#;
+        let kinds: Vec<MessageKind> = parse_messages(&mut &thread[..], ParseMode::Strict)
+            .unwrap()
+            .messages
+            .into_iter()
+            .map(|message| message.kind)
+            .collect();
+
+        let carol = vec![String::from("Carol")];
+        assert_eq!(
+            kinds,
+            vec![
+                MessageKind::System(SystemEvent::Unsent),
+                // Only the sender's own name counts
+                MessageKind::Text,
+                MessageKind::Text,
+                MessageKind::Text,
+                MessageKind::System(SystemEvent::Unsent),
+                MessageKind::System(SystemEvent::Nickname),
+                MessageKind::System(SystemEvent::Theme),
+                MessageKind::System(SystemEvent::Left(carol.clone())),
+                MessageKind::System(SystemEvent::Joined(carol)),
+                MessageKind::Call(None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_messages_lenient() {
+        let thread = br#"{
+            "participants": [{"name": "Alice"}, {"name": "Bob"}],
+            "messages": [
+                {"sender_name": "Bob", "timestamp_ms": 1609459203000, "content": "bad \u00c"},
+                {"timestamp_ms": 1609459202000, "content": "who?"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459201000,
+                 "content": "\u00ef\u00bf\u00bd"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459200000, "content": "hi"}
+            ],
+            "title": "Alice"
+        }"#;
+
+        assert!(parse_messages(&mut &thread[..], ParseMode::Strict).is_err());
+
+        let thread = parse_messages(&mut &thread[..], ParseMode::Lenient).unwrap();
+        assert_eq!(thread.title, "Alice");
+        assert_eq!(thread.participants.len(), 2);
+        assert_eq!(thread.messages.len(), 2);
+        assert_eq!(thread.messages[0].content, "\u{FFFD}");
+        assert_eq!(thread.messages[1].content, "hi");
+        assert_eq!(
+            thread.skipped.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert!(matches!(thread.skipped[0].1, Error::Encoding(_)));
+        assert!(matches!(thread.skipped[1].1, Error::Json(_)));
+
+        let thread = br#"{
+            "participants": [{"name": "Alice"}, {"name": "Bob \u00c"}],
+            "messages": [
+                {"sender_name": "Alice", "timestamp_ms": 1609459200000, "content": "hi"}
+            ],
+            "title": "Alice"
+        }"#;
+        assert!(matches!(
+            parse_messages(&mut &thread[..], ParseMode::Lenient),
+            Err(Error::Encoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_messages_broken_escapes() {
+        // Only the middle message is broken; its neighbours have escapes of
+        // their own that have to survive
+        let thread = br#"{
+            "participants": [{"name": "Alice"}, {"name": "Bob"}],
+            "messages": [
+                {"sender_name": "Bob", "timestamp_ms": 1609459202000,
+                 "content": "caf\u00c3\u00a9?"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459201000,
+                 "content": "bad \u00c3\u00a"},
+                {"sender_name": "Alice", "timestamp_ms": 1609459200000,
+                 "content": "na\u00c3\u00afve"}
+            ],
+            "title": "Alice"
+        }"#;
+        let thread = parse_messages(&mut &thread[..], ParseMode::Lenient).unwrap();
+        let contents: Vec<&str> = thread
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["café?", "naïve"]);
+        assert_eq!(
+            thread.skipped.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        let message = r#"{"sender_name": "Alice", "timestamp_ms": 1609459200000, "content": "hi"}"#;
+        for (title, participant) in [("Bob \\u00c", "Bob"), ("Bob", "Bob \\u00c")].iter() {
+            let thread = format!(
+                r#"{{"participants": [{{"name": "Alice"}}, {{"name": "{}"}}],
+                    "messages": [{}], "title": "{}"}}"#,
+                participant, message, title
+            );
+            assert!(matches!(
+                parse_messages(&mut thread.as_bytes(), ParseMode::Lenient),
+                Err(Error::Encoding(_))
+            ));
+        }
     }
 }
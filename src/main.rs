@@ -1,13 +1,203 @@
-use clap::{App, Arg, SubCommand};
-use multimap::MultiMap;
+use chrono::Duration;
+use clap::{App, Arg, ArgMatches, ErrorKind, SubCommand};
 use rand::SeedableRng;
 use rand_pcg::Pcg64Mcg;
 use std::fs::{create_dir, remove_file, File};
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::path::Path;
+use std::process::exit;
 
+use chat_log_parser_lib::authors::{normalize_authors, AuthorOptions, AuthorPolicy};
+use chat_log_parser_lib::format::{formatter, FORMATS};
+use chat_log_parser_lib::persona::persona_pairs;
+use chat_log_parser_lib::preference::preference_pairs;
+use chat_log_parser_lib::segment::ADAPTIVE_GAP_FACTOR;
+use chat_log_parser_lib::stats::Stats;
+use chat_log_parser_lib::tokenizer::Tokenizer;
 use chat_log_parser_lib::*;
 
+// Nothing useful can be done if the exports themselves can't be opened
+fn open_inputs(files: &[&str], mode: ParseMode) -> Box<dyn ChatSource> {
+    match expand_inputs(files).and_then(|paths| open_sources(&paths, mode)) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Couldn't open {:?}: {}", files, e);
+            exit(1);
+        }
+    }
+}
+
+// Flags read by `author_options`
+fn author_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("unknown-authors")
+            .long("unknown-authors")
+            .value_name("POLICY")
+            .help(
+                "What to do with deleted accounts and nameless authors: keep, drop, \
+                 pseudonymize, or any other name to call them that instead",
+            )
+            .default_value("keep")
+            .takes_value(true),
+        Arg::with_name("drop-unsent")
+            .long("drop-unsent")
+            .help("Leave out messages that were unsent"),
+    ]
+}
+
+fn lenient_arg() -> Arg<'static, 'static> {
+    Arg::with_name("lenient")
+        .long("lenient")
+        .help("Skip messages that can't be decoded instead of whole threads")
+}
+
+fn parse_mode(matches: &ArgMatches) -> ParseMode {
+    if matches.is_present("lenient") {
+        ParseMode::Lenient
+    } else {
+        ParseMode::Strict
+    }
+}
+
+// What --lenient left out, once everything has been read
+fn print_skipped(source: &mut dyn ChatSource) {
+    let skipped = source.take_skipped();
+    if !skipped.is_empty() {
+        println!(
+            "\n\nSkipped {} messages that couldn't be decoded:",
+            skipped.len()
+        );
+        for skipped in skipped.iter() {
+            println!("{} #{}: {}", skipped.path, skipped.index, skipped.error);
+        }
+    }
+}
+
+// The one conversation asked for with --name, or every one
+fn conversation_names(matches: &ArgMatches, source: &dyn ChatSource) -> Vec<String> {
+    match matches.value_of("name") {
+        Some(name) => vec![String::from(name)],
+        None => source.conversation_names(),
+    }
+}
+
+fn author_options(matches: &ArgMatches) -> AuthorOptions {
+    AuthorOptions {
+        unknown: match matches.value_of("unknown-authors") {
+            None | Some("keep") => AuthorPolicy::Keep,
+            Some("drop") => AuthorPolicy::Drop,
+            Some("pseudonymize") => AuthorPolicy::Pseudonymize,
+            Some(name) => AuthorPolicy::Map(String::from(name)),
+        },
+        drop_unsent: matches.is_present("drop-unsent"),
+    }
+}
+
+// Flags read by `segmenter`
+fn segmenter_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("segment")
+            .long("segment")
+            .value_name("POLICY")
+            .help(
+                "Where one conversation ends and the next begins: after --timeout, after a gap \
+                 much longer than usual for the people talking, or at midnight UTC",
+            )
+            .possible_values(&["timeout", "adaptive", "day"])
+            .default_value("timeout")
+            .takes_value(true),
+        Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("SECONDS")
+            .help("How long a silence ends a conversation, or the shortest one for adaptive")
+            .default_value("600")
+            .takes_value(true),
+        Arg::with_name("max-segment-messages")
+            .long("max-segment-messages")
+            .value_name("N")
+            .help("Cut conversations longer than this many messages into pieces")
+            .takes_value(true),
+    ]
+}
+
+fn segmenter(matches: &ArgMatches) -> Segmenter {
+    let timeout = Duration::seconds(matches.value_of("timeout").unwrap().parse::<i64>().unwrap());
+    let segmenter = Segmenter::new(match matches.value_of("segment") {
+        Some("adaptive") => SegmentPolicy::Adaptive {
+            factor: ADAPTIVE_GAP_FACTOR,
+            min: timeout,
+            max: Duration::days(1),
+        },
+        Some("day") => SegmentPolicy::Day,
+        _ => SegmentPolicy::Timeout(timeout),
+    });
+    match matches.value_of("max-segment-messages") {
+        Some(max) => segmenter.with_max_messages(max.parse::<usize>().unwrap()),
+        None => segmenter,
+    }
+}
+
+// Flags read by `merge_bursts`
+fn turn_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("merge-turns")
+            .long("merge-turns")
+            .value_name("SECONDS")
+            .help(
+                "Merge messages someone sent within this many seconds of each other into one \
+                 turn",
+            )
+            .takes_value(true),
+        Arg::with_name("turn-joiner")
+            .long("turn-joiner")
+            .value_name("TEXT")
+            .help("What to put between the messages of a merged turn, a newline by default")
+            .default_value("\n")
+            .hide_default_value(true)
+            .takes_value(true),
+    ]
+}
+
+// Bursts of messages become single turns before anything else sees them
+fn merge_bursts(matches: &ArgMatches, conversation: &mut Conversation) {
+    if let Some(max_gap) = matches.value_of("merge-turns") {
+        conversation.messages = merge_turns(
+            &conversation.messages,
+            Duration::seconds(max_gap.parse::<i64>().unwrap()),
+            matches.value_of("turn-joiner").unwrap(),
+        );
+    }
+}
+
+// Reads each conversation in turn and hands it on, with its authors
+// normalized and bursts merged as the shared flags asked
+fn read_each<F: FnMut(Conversation)>(
+    source: &mut dyn ChatSource,
+    names: &[String],
+    matches: &ArgMatches,
+    mut f: F,
+) {
+    let author_options = author_options(matches);
+    for name in names.iter() {
+        let mut conversation = match source.read_conversation(name) {
+            Ok(Some(conversation)) => conversation,
+            Ok(None) => {
+                println!("No messages found for {}", name);
+                continue;
+            }
+            // One broken thread shouldn't throw away every other one
+            Err(e) => {
+                eprintln!("Skipping {}: {}", name, e);
+                continue;
+            }
+        };
+
+        normalize_authors(&mut conversation, &author_options);
+        merge_bursts(matches, &mut conversation);
+        f(conversation);
+    }
+}
+
 fn main() {
     // this is kind of gross and doesn't work well,
     // refactor later
@@ -15,8 +205,8 @@ fn main() {
         .version("0.1")
         .author("Srinvas Kaza <kazasrinivas3@gmail.com>")
         .about(
-            "Converts a zip of Facebook messenger data into
-               a suitable format for GPT2",
+            "Converts exported chat logs (Facebook Messenger, WhatsApp,
+               Telegram or Discord) into a suitable format for GPT2",
         )
         .subcommand(
             SubCommand::with_name("list")
@@ -24,7 +214,9 @@ fn main() {
                 .arg(
                     Arg::with_name("input")
                         .value_name("FILE")
+                        .help("Exports to read -- several, or a glob, are merged into one")
                         .required(true)
+                        .multiple(true)
                         .takes_value(true),
                 ),
         )
@@ -41,7 +233,9 @@ fn main() {
                 .arg(
                     Arg::with_name("input")
                         .value_name("FILE")
+                        .help("Exports to read -- several, or a glob, are merged into one")
                         .required(true)
+                        .multiple(true)
                         .takes_value(true),
                 )
                 .arg(
@@ -67,178 +261,565 @@ fn main() {
                         .value_name("FILE")
                         .required(true)
                         .takes_value(true),
-                ),
+                )
+                .args(&segmenter_args())
+                .arg(
+                    Arg::with_name("test-gap")
+                        .long("test-gap")
+                        .value_name("DAYS")
+                        .help(
+                            "How many whole days apart two conversations have to start before \
+                             --test can put them in different sets",
+                        )
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tokenizer")
+                        .long("tokenizer")
+                        .value_name("PATH")
+                        .help(
+                            "A tokenizer.json, or GPT-2's vocab.json with merges.txt next to it, \
+                             to count tokens with",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-tokens")
+                        .long("max-tokens")
+                        .value_name("N")
+                        .help("Split conversations longer than this many tokens between messages")
+                        .requires("tokenizer")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("overlap")
+                        .long("overlap")
+                        .value_name("MESSAGES")
+                        .help("Start each piece of a split conversation with this many messages from the last")
+                        .requires("max-tokens")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("window")
+                        .long("window")
+                        .value_name("MESSAGES")
+                        .help("Write overlapping windows of this many messages instead of whole conversations")
+                        .conflicts_with("window-tokens")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("window-tokens")
+                        .long("window-tokens")
+                        .value_name("N")
+                        .help("Write overlapping windows of as many messages as fit in this many tokens")
+                        .requires("tokenizer")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("stride")
+                        .long("stride")
+                        .value_name("MESSAGES")
+                        .help("How many messages each window starts after the last, 1 by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .short("f")
+                        .value_name("FORMAT")
+                        .help("Layout of the dataset")
+                        .possible_values(FORMATS)
+                        .default_value("gpt2")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("persona")
+                        .long("persona")
+                        .value_name("NAME")
+                        .help("Whose messages a chat model should learn to write")
+                        .required_ifs(&[("format", "chatml"), ("format", "sharegpt")])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("system-prompt")
+                        .long("system-prompt")
+                        .value_name("PROMPT")
+                        .help("Put at the start of every chat example")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reactions")
+                        .long("reactions")
+                        .help("Follow each message with who reacted to it, and how"),
+                )
+                .arg(
+                    Arg::with_name("replies")
+                        .long("replies")
+                        .value_name("STYLE")
+                        .help("Show what replies reply to by quoting it inline, or write only the pairs (gpt2 only)")
+                        .possible_values(&["ignore", "inline", "pairs"])
+                        .default_value("ignore")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("system-events")
+                        .long("system-events")
+                        .value_name("STYLE")
+                        .help("How to write calls, people joining or leaving, nickname changes...")
+                        .possible_values(&["text", "drop", "token", "separate"])
+                        .default_value("text")
+                        .takes_value(true),
+                )
+                .args(&author_args())
+                .args(&turn_args())
+                .arg(lenient_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("preferences")
+                .about("Generates a preference dataset from replies people reacted to")
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .required(false)
+                        .short("n")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("FILE")
+                        .help("Exports to read -- several, or a glob, are merged into one")
+                        .required(true)
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .args(&segmenter_args())
+                .args(&author_args())
+                .args(&turn_args())
+                .arg(lenient_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("persona")
+                .about("Generates prompt/completion pairs for everything one person said")
+                .arg(
+                    Arg::with_name("persona")
+                        .long("persona")
+                        .value_name("NAME")
+                        .help("Whose messages are the completions")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("FILE")
+                        .help("Exports to read -- several, or a glob, are merged into one")
+                        .required(true)
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("context")
+                        .long("context")
+                        .short("c")
+                        .value_name("N")
+                        .help("How many messages before each completion to put in its prompt")
+                        .default_value("5")
+                        .takes_value(true),
+                )
+                .args(&segmenter_args())
+                .args(&author_args())
+                .args(&turn_args())
+                .arg(lenient_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Counts messages, words and conversations, and who sent them")
+                .arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .required(false)
+                        .short("n")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("FILE")
+                        .help("Exports to read -- several, or a glob, are merged into one")
+                        .required(true)
+                        .multiple(true)
+                        .takes_value(true),
+                )
+                .args(&turn_args())
+                .args(&segmenter_args())
+                .args(&author_args())
+                .arg(lenient_arg()),
         )
         .get_matches();
 
     match matches.subcommand_name() {
         Some("list") => {
             // refactor this later
-            let fb_file = matches
+            let fb_files: Vec<&str> = matches
                 .subcommand_matches("list")
                 .unwrap()
-                .value_of("input")
-                .unwrap();
+                .values_of("input")
+                .unwrap()
+                .collect();
 
-            for conversation in list(fb_file) {
+            let source = open_inputs(&fb_files, ParseMode::Strict);
+            for conversation in list(source.as_ref()) {
                 println!("{}", conversation);
             }
         }
         Some("generate") => {
             let generate_match = matches.subcommand_matches("generate").unwrap();
-            let (fb_file, name, output_file_path, test_ratio, seed) = (
-                generate_match.value_of("input").unwrap(),
-                generate_match.value_of("name"),
+            let (fb_files, output_file_path, test_ratio, seed, mode) = (
+                generate_match
+                    .values_of("input")
+                    .unwrap()
+                    .collect::<Vec<&str>>(),
                 generate_match.value_of("output").unwrap(),
-                match generate_match.value_of("test") {
-                    None => None,
-                    Some(test_ratio) => Some(test_ratio.parse::<f32>().unwrap()),
-                },
-                match generate_match.value_of("seed") {
-                    None => None,
-                    Some(seed) => Some(seed.parse::<u64>().unwrap()),
-                },
+                generate_match
+                    .value_of("test")
+                    .map(|test_ratio| test_ratio.parse::<f32>().unwrap()),
+                generate_match
+                    .value_of("seed")
+                    .map(|seed| seed.parse::<u64>().unwrap()),
+                parse_mode(generate_match),
             );
 
+            // clap can only check that flags conflict, not their values
+            if generate_match.value_of("replies") == Some("pairs")
+                && generate_match.value_of("format") != Some("gpt2")
+            {
+                clap::Error::with_description(
+                    "--replies pairs only works with --format gpt2",
+                    ErrorKind::ArgumentConflict,
+                )
+                .exit();
+            }
+
             if !Path::new(output_file_path).exists() {
                 create_dir(output_file_path).unwrap();
             }
 
-            match test_ratio {
-                None => {}
-                Some(test_ratio) => {
-                    assert!(test_ratio < 1.0 && test_ratio > 0.0);
-                }
-            };
-            let zip_file = File::open(fb_file).unwrap();
-            let mut zip = zip::ZipArchive::new(zip_file).unwrap();
-
-            // Maps from a String of the conversation name -> all conversation zip file IDs
-            let all_conversations: MultiMap<String, usize> = get_all_conversations(&mut zip);
-
-            let all_conversations = match name {
-                Some(name) => {
-                    let mut map: MultiMap<String, usize> = MultiMap::new();
-                    map.insert_many_from_slice(
-                        name.into(),
-                        all_conversations.get_vec(name).unwrap(),
-                    );
-                    map
-                }
-                None => all_conversations,
+            if let Some(test_ratio) = test_ratio {
+                assert!(test_ratio < 1.0 && test_ratio > 0.0);
+            }
+            let format_options = FormatOptions {
+                reactions: generate_match.is_present("reactions"),
+                events: match generate_match.value_of("system-events") {
+                    Some("drop") => EventStyle::Drop,
+                    Some("token") => EventStyle::Token,
+                    Some("separate") => EventStyle::Separate,
+                    _ => EventStyle::Text,
+                },
+                replies: match generate_match.value_of("replies") {
+                    Some("inline") => ReplyStyle::Inline,
+                    Some("pairs") => ReplyStyle::Pairs,
+                    _ => ReplyStyle::Ignore,
+                },
+                persona: generate_match.value_of("persona").map(String::from),
+                system_prompt: generate_match.value_of("system-prompt").map(String::from),
             };
+            let formatter = formatter(
+                generate_match.value_of("format").unwrap(),
+                format_options.clone(),
+            )
+            .unwrap();
+            let segmenter = segmenter(generate_match);
+            let tokenizer = generate_match.value_of("tokenizer").map(|path| {
+                Tokenizer::open(path).unwrap_or_else(|e| {
+                    eprintln!("Couldn't load tokenizer {}: {}", path, e);
+                    exit(1);
+                })
+            });
+            let max_tokens = generate_match
+                .value_of("max-tokens")
+                .map(|max_tokens| max_tokens.parse::<usize>().unwrap());
+            let overlap = generate_match
+                .value_of("overlap")
+                .map_or(0, |overlap| overlap.parse::<usize>().unwrap());
+            let window = generate_match
+                .value_of("window")
+                .map(|window| window.parse::<usize>().unwrap());
+            let window_tokens = generate_match
+                .value_of("window-tokens")
+                .map(|window_tokens| window_tokens.parse::<usize>().unwrap());
+            let stride = generate_match
+                .value_of("stride")
+                .map_or(1, |stride| stride.parse::<usize>().unwrap());
+            let test_gap = generate_match
+                .value_of("test-gap")
+                .unwrap()
+                .parse::<i64>()
+                .unwrap();
+            let mut token_counts = Vec::new();
+            let mut source = open_inputs(&fb_files, mode);
 
-            let write_msgs = |msgs: &[Message],
-                              participants: &[Participant],
-                              name: &str,
-                              suffix: Option<&'static str>| {
+            let conversation_names = conversation_names(generate_match, source.as_ref());
+
+            let mut write_msgs = |msgs: &[Message],
+                                  participants: &[Participant],
+                                  name: &str,
+                                  suffix: Option<&'static str>| {
                 let out_parent_path = Path::new(output_file_path);
-                let output_file_name: String = match suffix {
-                    None => String::from(name),
-                    Some(suffix) => format!("{}_{}.{}", name, suffix, ".txt"),
-                }
-                .into();
+                let output_file_name: String = match (suffix, formatter.extension()) {
+                    (None, None) => String::from(name),
+                    (None, Some(extension)) => format!("{}.{}", name, extension),
+                    (Some(suffix), extension) => {
+                        format!("{}_{}.{}", name, suffix, extension.unwrap_or("txt"))
+                    }
+                };
                 let output_file_name = Path::new(&output_file_name);
 
                 let out_path = out_parent_path.join(output_file_name);
 
-                match remove_file(&out_path) {
-                    Ok(_) => println!("Warning: Overwriting {:?}", &out_path),
-                    Err(_) => {}
-                };
-
-                let mut output_file = File::create(out_path).unwrap();
-                let formatted_messages =
-                    format_conversation(msgs, participants, "|EOM|", "<|endoftext|>");
-                output_file.write(formatted_messages.as_bytes()).unwrap();
-            };
+                if remove_file(&out_path).is_ok() {
+                    println!("Warning: Overwriting {:?}", &out_path);
+                }
 
-            for conversation_name in all_conversations.keys() {
-                let mut title = None;
-                let mut prev_participants = None;
+                let mut segmented = Segmented::new(msgs, participants, &segmenter, &format_options);
+                if let (Some(tokenizer), Some(max_tokens)) = (&tokenizer, max_tokens) {
+                    let too_long =
+                        segmented.fit_to_budget(formatter.as_ref(), tokenizer, max_tokens, overlap);
+                    if too_long > 0 {
+                        println!(
+                            "Warning: {} messages in {:?} are over {} tokens on their own",
+                            too_long, &out_path, max_tokens
+                        );
+                    }
+                }
 
-                let conversation_idx = all_conversations.get_vec(conversation_name).unwrap();
+                // Windows are taken from each side of a train/test split separately,
+                // so none of them spans both
+                let window_size = match (window, window_tokens, &tokenizer) {
+                    (Some(count), _, _) => Some(WindowSize::Messages(count)),
+                    (None, Some(max_tokens), Some(tokenizer)) => Some(WindowSize::Tokens(
+                        formatter.as_ref(),
+                        tokenizer,
+                        max_tokens,
+                    )),
+                    _ => None,
+                };
+                if let Some(window_size) = window_size {
+                    segmented.sliding_windows(window_size, stride);
+                }
 
-                let mut conversation_messages: Vec<Message> = Vec::new();
+                let mut output_file = BufWriter::new(File::create(&out_path).unwrap());
+                match &tokenizer {
+                    None => formatter
+                        .write_conversation(&mut output_file, &segmented)
+                        .unwrap(),
+                    Some(tokenizer) => {
+                        let mut formatted = Vec::new();
+                        formatter
+                            .write_conversation(&mut formatted, &segmented)
+                            .unwrap();
+                        let tokens = tokenizer.count(&String::from_utf8_lossy(&formatted));
+                        token_counts.push((out_path, tokens));
+                        output_file.write_all(&formatted).unwrap();
+                    }
+                }
+                output_file.flush().unwrap();
+            };
 
-                // TODO: Tagged message is probably not the right approach. We need to segment
-                // each conversation into exchanges first, and then insert those by timestamp.
-                // format_conversation will need to be removed
-                for (i, &idx) in conversation_idx.iter().enumerate() {
-                    let mut zip_file = zip.by_index(idx).unwrap();
-                    let (_title, _participants, mut messages) =
-                        parse_messages(&mut zip_file).unwrap();
+            read_each(
+                source.as_mut(),
+                &conversation_names,
+                generate_match,
+                |conversation| {
+                    println!(
+                        "\n\nConversation title: {}\nParticipants: {:?}",
+                        conversation.title, conversation.participants
+                    );
 
-                    if messages.len() == 0 {
-                        continue;
-                    }
+                    match test_ratio {
+                        None => {
+                            write_msgs(
+                                &conversation.messages,
+                                &conversation.participants,
+                                &conversation.name,
+                                None,
+                            );
+                        }
+                        Some(test_ratio) => {
+                            let mut rng = match seed {
+                                Some(seed) => Pcg64Mcg::seed_from_u64(seed),
+                                None => Pcg64Mcg::from_entropy(),
+                            };
+                            let (train_messages, test_messages) = train_test(
+                                &conversation.messages,
+                                test_ratio,
+                                &mut rng,
+                                &segmenter,
+                                test_gap,
+                            );
 
-                    // In a given conversation, we don't expect the participants to change
-                    match prev_participants {
-                        Some(prev_participants) => {
-                            assert!(prev_participants == _participants);
+                            write_msgs(
+                                &train_messages,
+                                &conversation.participants,
+                                &conversation.name,
+                                Some("train"),
+                            );
+                            write_msgs(
+                                &test_messages,
+                                &conversation.participants,
+                                &conversation.name,
+                                Some("test"),
+                            );
                         }
-                        None => {}
                     };
+                },
+            );
 
-                    prev_participants = Some(_participants);
-                    title = Some(_title);
-                    println!(
-                        "Parsed {} messages from compressed json file {} -- {:.2} MB",
-                        &messages.len(),
-                        i,
-                        (zip_file.size() as f64) / (1 << 20) as f64
-                    );
-
-                    conversation_messages.append(&mut messages);
+            if !token_counts.is_empty() {
+                println!("\n\nTokens written:");
+                for (path, tokens) in token_counts.iter() {
+                    println!("{:?}: {}", path, tokens);
                 }
-
-                conversation_messages.sort_by_key(|a| a.timestamp);
                 println!(
-                    "Sorted {} messages by timestamp",
-                    conversation_messages.len()
+                    "Total: {}",
+                    token_counts.iter().map(|(_, tokens)| tokens).sum::<usize>()
                 );
+            }
+            print_skipped(source.as_mut());
+        }
+        Some("preferences") => {
+            let preferences_match = matches.subcommand_matches("preferences").unwrap();
+            let fb_files: Vec<&str> = preferences_match.values_of("input").unwrap().collect();
+            let output_file_path = preferences_match.value_of("output").unwrap();
+            let mode = parse_mode(preferences_match);
 
-                let prev_participants = prev_participants.unwrap();
+            if !Path::new(output_file_path).exists() {
+                create_dir(output_file_path).unwrap();
+            }
+            let segmenter = segmenter(preferences_match);
+            let mut source = open_inputs(&fb_files, mode);
 
-                println!(
-                    "\n\nConversation title: {}\nParticipants: {:?}",
-                    title.unwrap(),
-                    prev_participants
-                );
+            let conversation_names = conversation_names(preferences_match, source.as_ref());
 
-                match test_ratio {
-                    None => {
-                        write_msgs(
-                            &conversation_messages,
-                            &prev_participants,
-                            conversation_name,
-                            None,
-                        );
+            read_each(
+                source.as_mut(),
+                &conversation_names,
+                preferences_match,
+                |conversation| {
+                    let pairs = preference_pairs(&conversation.messages, &segmenter);
+                    println!(
+                        "Found {} preference pairs in {}",
+                        pairs.len(),
+                        conversation.title
+                    );
+                    if pairs.is_empty() {
+                        return;
                     }
-                    Some(test_ratio) => {
-                        let mut rng = match seed {
-                            Some(seed) => Pcg64Mcg::seed_from_u64(seed),
-                            None => Pcg64Mcg::from_entropy(),
-                        };
-                        let (train_messages, test_messages) =
-                            train_test(&conversation_messages, test_ratio, &mut rng);
-
-                        write_msgs(
-                            &train_messages,
-                            &prev_participants,
-                            conversation_name,
-                            Some("train"),
-                        );
-                        write_msgs(
-                            &test_messages,
-                            &prev_participants,
-                            conversation_name,
-                            Some("test"),
-                        );
+
+                    let out_path = Path::new(output_file_path)
+                        .join(format!("{}_preferences.jsonl", conversation.name));
+                    if remove_file(&out_path).is_ok() {
+                        println!("Warning: Overwriting {:?}", &out_path);
                     }
-                };
+
+                    let mut output_file = File::create(out_path).unwrap();
+                    for pair in pairs.iter() {
+                        writeln!(output_file, "{}", serde_json::to_string(pair).unwrap()).unwrap();
+                    }
+                },
+            );
+            print_skipped(source.as_mut());
+        }
+        Some("persona") => {
+            let persona_match = matches.subcommand_matches("persona").unwrap();
+            let fb_files: Vec<&str> = persona_match.values_of("input").unwrap().collect();
+            let output_file_path = persona_match.value_of("output").unwrap();
+            let persona = persona_match.value_of("persona").unwrap();
+            let context = persona_match
+                .value_of("context")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            let mode = parse_mode(persona_match);
+
+            if !Path::new(output_file_path).exists() {
+                create_dir(output_file_path).unwrap();
             }
+            let segmenter = segmenter(persona_match);
+            let mut source = open_inputs(&fb_files, mode);
+
+            let file_name: String = persona
+                .to_lowercase()
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            let out_path = Path::new(output_file_path).join(format!("{}_persona.jsonl", file_name));
+            if remove_file(&out_path).is_ok() {
+                println!("Warning: Overwriting {:?}", &out_path);
+            }
+            let mut output_file = BufWriter::new(File::create(out_path).unwrap());
+
+            // Everything they said anywhere goes into the one dataset
+            let mut total = 0;
+            let conversation_names = source.conversation_names();
+            read_each(
+                source.as_mut(),
+                &conversation_names,
+                persona_match,
+                |conversation| {
+                    let pairs = persona_pairs(&conversation.messages, persona, context, &segmenter);
+                    println!(
+                        "Found {} replies by {} in {}",
+                        pairs.len(),
+                        persona,
+                        conversation.title
+                    );
+                    total += pairs.len();
+                    for pair in pairs.iter() {
+                        writeln!(output_file, "{}", serde_json::to_string(pair).unwrap()).unwrap();
+                    }
+                },
+            );
+            output_file.flush().unwrap();
+            println!("\n\nWrote {} pairs", total);
+            print_skipped(source.as_mut());
+        }
+        Some("stats") => {
+            let stats_match = matches.subcommand_matches("stats").unwrap();
+            let fb_files: Vec<&str> = stats_match.values_of("input").unwrap().collect();
+            let mode = parse_mode(stats_match);
+            let segmenter = segmenter(stats_match);
+            let mut source = open_inputs(&fb_files, mode);
+
+            let conversation_names = conversation_names(stats_match, source.as_ref());
+
+            let mut total = Stats::default();
+            read_each(
+                source.as_mut(),
+                &conversation_names,
+                stats_match,
+                |conversation| {
+                    let stats = Stats::new(&conversation.messages, &segmenter);
+                    println!("\n{}\n{}", conversation.title, stats);
+                    total.add(&stats);
+                },
+            );
+            println!("\nTotal\n{}", total);
+            print_skipped(source.as_mut());
         }
         e => {
             println!("Invalid option {:?}!", e);
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+use crate::{Message, MessageKind, Participant, SystemEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Joined,
+    Left,
+    // Saying something means you're there, whether or not the export
+    // recorded you joining
+    Spoke,
+}
+
+/// Who was in a group chat over time, worked out from people being added,
+/// leaving or being removed, and from who wrote what when
+pub struct Membership {
+    // Everyone who was ever in the chat, in the order they're first seen
+    people: Vec<String>,
+    timelines: HashMap<String, Vec<(DateTime<Utc>, Change)>>,
+}
+
+impl Membership {
+    /// `messages` must be sorted by timestamp
+    pub fn new(participants: &[Participant], messages: &[Message]) -> Membership {
+        let mut membership = Membership {
+            people: Vec::new(),
+            timelines: HashMap::new(),
+        };
+        for participant in participants {
+            membership.add_person(&participant.name);
+        }
+
+        for message in messages {
+            membership.record(&message.author, message.timestamp, Change::Spoke);
+            match &message.kind {
+                MessageKind::System(SystemEvent::Joined(names)) => {
+                    for name in names {
+                        membership.record(name, message.timestamp, Change::Joined);
+                    }
+                }
+                MessageKind::System(SystemEvent::Left(names)) => {
+                    for name in names {
+                        membership.record(name, message.timestamp, Change::Left);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        membership
+    }
+
+    fn add_person(&mut self, name: &str) {
+        if !self.people.iter().any(|person| person == name) {
+            self.people.push(String::from(name));
+        }
+    }
+
+    fn record(&mut self, name: &str, timestamp: DateTime<Utc>, change: Change) {
+        self.add_person(name);
+        self.timelines
+            .entry(String::from(name))
+            .or_default()
+            .push((timestamp, change));
+    }
+
+    /// Everyone who was in the chat at any point between `start` and `end`.
+    /// People are assumed to have been there from the start, unless the
+    /// first thing known about them is being added
+    pub fn present_during(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<String> {
+        self.people
+            .iter()
+            .filter(|person| {
+                let timeline = match self.timelines.get(*person) {
+                    Some(timeline) => timeline,
+                    None => return true,
+                };
+
+                let mut present = timeline[0].1 != Change::Joined;
+                for &(timestamp, change) in timeline {
+                    if timestamp > end {
+                        break;
+                    }
+                    match change {
+                        Change::Joined | Change::Spoke if timestamp >= start => return true,
+                        Change::Joined | Change::Spoke => present = true,
+                        Change::Left => present = false,
+                    }
+                }
+                present
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_present_during() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let at = |hours| start + Duration::hours(hours);
+        let event = |author, event, hours| {
+            let mut message = Message::text(author, "", at(hours));
+            message.kind = MessageKind::System(event);
+            message
+        };
+        let participants: Vec<Participant> = ["Alice", "Bob", "Dan"]
+            .iter()
+            .map(|name| Participant {
+                name: String::from(*name),
+            })
+            .collect();
+        let messages = vec![
+            Message::text("Alice", "hi", at(0)),
+            event("Alice", SystemEvent::Joined(vec![String::from("Carol")]), 2),
+            Message::text("Carol", "hello", at(3)),
+            event("Bob", SystemEvent::Left(vec![String::from("Bob")]), 4),
+            Message::text("Alice", "bye bob", at(6)),
+        ];
+        let membership = Membership::new(&participants, &messages);
+
+        assert_eq!(
+            membership.present_during(at(0), at(1)),
+            vec!["Alice", "Bob", "Dan"]
+        );
+        assert_eq!(
+            membership.present_during(at(2), at(4)),
+            vec!["Alice", "Bob", "Dan", "Carol"]
+        );
+        assert_eq!(
+            membership.present_during(at(6), at(6)),
+            vec!["Alice", "Dan", "Carol"]
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use serde::Serialize;
+
+use crate::{Message, Segmenter};
+
+/// One line of a prompt/completion dataset for a bot that talks like one
+/// person: what was said before, and what they said next
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PersonaPair {
+    pub prompt: String,
+    pub completion: String,
+}
+
+// Calls and system events aren't something anyone said
+fn is_said(message: &Message) -> bool {
+    message.kind.event_name().is_none() && !message.render_content().is_empty()
+}
+
+fn format_prompt(context: &[&Message], author: &str) -> String {
+    let mut prompt: String = context
+        .iter()
+        .map(|message| format!("{}: {}\n", message.author, message.render_content()))
+        .collect();
+    prompt.push_str(&format!("{}: ", author));
+    prompt
+}
+
+/// Pairs every message by `persona` with up to `context` messages before it
+/// in the same conversation, split by `segmenter`. Messages that start a
+/// conversation have nothing to reply to, so they're left out
+pub fn persona_pairs(
+    conversation: &[Message],
+    persona: &str,
+    context: usize,
+    segmenter: &Segmenter,
+) -> Vec<PersonaPair> {
+    let said: Vec<&Message> = conversation.iter().filter(|m| is_said(m)).collect();
+    let mut pairs = Vec::new();
+    for segment in segmenter.split(&said) {
+        for (i, message) in segment.iter().enumerate().skip(1) {
+            if message.author != persona {
+                continue;
+            }
+
+            pairs.push(PersonaPair {
+                prompt: format_prompt(&segment[i.saturating_sub(context)..i], persona),
+                completion: message.render_content(),
+            });
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageKind, SystemEvent};
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn test_persona_pairs() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let at = |seconds| start + Duration::seconds(seconds);
+        let mut joined = Message::text("Bob", "Bob added Carol to the group.", at(1));
+        joined.kind = MessageKind::System(SystemEvent::Joined(vec![String::from("Carol")]));
+        let conversation = vec![
+            Message::text("Alice", "hi", at(0)),
+            joined,
+            Message::text("Carol", "hello", at(2)),
+            Message::text("Bob", "hey all", at(3)),
+            Message::text("Bob", "dinner?", at(4)),
+            // A new conversation, so the context starts over
+            Message::text("Bob", "anyone?", at(3600)),
+            Message::text("Alice", "sure", at(3610)),
+            Message::text("Bob", "great", at(3620)),
+        ];
+
+        assert_eq!(
+            persona_pairs(&conversation, "Bob", 2, &Segmenter::default()),
+            vec![
+                PersonaPair {
+                    prompt: String::from("Alice: hi\nCarol: hello\nBob: "),
+                    completion: String::from("hey all"),
+                },
+                PersonaPair {
+                    prompt: String::from("Carol: hello\nBob: hey all\nBob: "),
+                    completion: String::from("dinner?"),
+                },
+                PersonaPair {
+                    prompt: String::from("Bob: anyone?\nAlice: sure\nBob: "),
+                    completion: String::from("great"),
+                },
+            ]
+        );
+    }
+}
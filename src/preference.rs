@@ -0,0 +1,199 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{Message, MessageKind, Segmenter};
+
+// Slack names its emoji instead of using the characters
+const POSITIVE_REACTIONS: &[&str] = &[
+    "❤",
+    "😆",
+    "👍",
+    ":heart:",
+    ":laughing:",
+    ":+1:",
+    ":thumbsup:",
+];
+const NEGATIVE_REACTIONS: &[&str] = &["😠", "😢", "👎", ":angry:", ":cry:", ":-1:", ":thumbsdown:"];
+
+/// One line of a DPO-style preference dataset: two replies by the same
+/// person to the same context, one of which landed better than the other
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PreferencePair {
+    pub prompt: String,
+    pub chosen: String,
+    pub rejected: String,
+}
+
+/// Positive reactions minus negative ones. Reacting to your own message
+/// doesn't count
+fn reaction_score(message: &Message) -> i32 {
+    message
+        .reactions
+        .iter()
+        .filter(|reaction| reaction.actor != message.author)
+        .map(|reaction| {
+            // Messenger sometimes adds a variation selector
+            let emoji = reaction.reaction.trim_end_matches('\u{FE0F}');
+            if POSITIVE_REACTIONS.contains(&emoji) {
+                1
+            } else if NEGATIVE_REACTIONS.contains(&emoji) {
+                -1
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+fn is_reply(message: &Message) -> bool {
+    message.kind == MessageKind::Text && !message.content.is_empty()
+}
+
+fn is_chosen(message: &Message) -> bool {
+    is_reply(message) && reaction_score(message) > 0
+}
+
+fn is_rejected(message: &Message) -> bool {
+    is_reply(message) && (message.reactions.is_empty() || reaction_score(message) < 0)
+}
+
+fn format_prompt(context: &[Message], author: &str) -> String {
+    let mut prompt: String = context
+        .iter()
+        .map(|message| format!("{}: {}\n", message.author, message.render_content()))
+        .collect();
+    prompt.push_str(&format!("{}: ", author));
+    prompt
+}
+
+/// Pairs every reply that got positive reactions (❤, 😆, 👍) with the
+/// closest reply by the same person in the same conversation, split by
+/// `segmenter`, that got no reactions or negative ones. Replies with nothing
+/// to compare against are left out. The prompt is everything said before
+/// the earlier of the two replies in that conversation, so it never holds
+/// either of them
+pub fn preference_pairs(conversation: &[Message], segmenter: &Segmenter) -> Vec<PreferencePair> {
+    let mut pairs = Vec::new();
+    for segment in segmenter.split(conversation) {
+        // Where each author's rejected replies are, in order. Like chosen
+        // ones, a reply that opens the conversation has no prompt
+        let mut rejected_at: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, message) in segment.iter().enumerate().skip(1) {
+            if is_rejected(message) {
+                rejected_at.entry(&message.author).or_default().push(i);
+            }
+        }
+
+        for (i, chosen) in segment.iter().enumerate().skip(1) {
+            if !is_chosen(chosen) {
+                continue;
+            }
+            let candidates = match rejected_at.get(chosen.author.as_str()) {
+                Some(candidates) => candidates,
+                None => continue,
+            };
+
+            // The segment is sorted, so the closest is right before or after
+            let after = candidates.partition_point(|&j| j < i);
+            let closest = [after.checked_sub(1), Some(after)]
+                .iter()
+                .filter_map(|j| candidates.get((*j)?))
+                .copied()
+                .min_by_key(|&j| {
+                    segment[j]
+                        .timestamp
+                        .signed_duration_since(chosen.timestamp)
+                        .num_milliseconds()
+                        .abs()
+                });
+            if let Some(j) = closest {
+                pairs.push(PreferencePair {
+                    prompt: format_prompt(&segment[..i.min(j)], &chosen.author),
+                    chosen: chosen.content.clone(),
+                    rejected: segment[j].content.clone(),
+                });
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Reaction;
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn reacted(mut message: Message, reaction: &str, actor: &str) -> Message {
+        message.reactions.push(Reaction {
+            reaction: String::from(reaction),
+            actor: String::from(actor),
+        });
+        message
+    }
+
+    #[test]
+    fn test_preference_pairs() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let at = |seconds| start + Duration::seconds(seconds);
+        let conversation = vec![
+            Message::text("Bob", "what's for dinner", at(0)),
+            reacted(Message::text("Alice", "tacos", at(10)), "❤", "Bob"),
+            Message::text("Alice", "or soup", at(20)),
+            // Liked, but only by herself
+            reacted(Message::text("Alice", "soup it is", at(30)), "👍", "Alice"),
+            // A new conversation, so the prompt starts over
+            Message::text("Bob", "movie?", at(3600)),
+            reacted(
+                Message::text("Alice", "sure", at(3610)),
+                "👍\u{FE0F}",
+                "Bob",
+            ),
+            reacted(Message::text("Alice", "which one", at(3620)), "😠", "Bob"),
+            // Nothing of hers to compare against in this one
+            Message::text("Bob", "tomorrow?", at(7200)),
+            reacted(Message::text("Alice", "yes", at(7210)), "❤", "Bob"),
+        ];
+
+        let pairs = preference_pairs(&conversation, &Segmenter::default());
+        assert_eq!(
+            pairs,
+            vec![
+                PreferencePair {
+                    prompt: String::from("Bob: what's for dinner\nAlice: "),
+                    chosen: String::from("tacos"),
+                    rejected: String::from("or soup"),
+                },
+                PreferencePair {
+                    prompt: String::from("Bob: movie?\nAlice: "),
+                    chosen: String::from("sure"),
+                    rejected: String::from("which one"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preference_pairs_rejected_first() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let at = |seconds| start + Duration::seconds(seconds);
+        let conversation = vec![
+            Message::text("Bob", "what's for dinner", at(0)),
+            Message::text("Alice", "no idea", at(10)),
+            Message::text("Bob", "come on", at(20)),
+            reacted(Message::text("Alice", "tacos", at(30)), "❤", "Bob"),
+        ];
+
+        // The prompt stops before the rejected reply, so it can't give it away
+        let pairs = preference_pairs(&conversation, &Segmenter::default());
+        assert_eq!(
+            pairs,
+            vec![PreferencePair {
+                prompt: String::from("Bob: what's for dinner\nAlice: "),
+                chosen: String::from("tacos"),
+                rejected: String::from("no idea"),
+            }]
+        );
+        assert!(!pairs[0].prompt.contains(&pairs[0].rejected));
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::Duration;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+
+use crate::{Message, CONVERSATION_TIMEOUT};
+
+/// How many times longer than a pair's typical reply a gap has to be for
+/// `SegmentPolicy::Adaptive` to start a new segment
+pub const ADAPTIVE_GAP_FACTOR: f64 = 5.0;
+
+// Pairs with fewer replies than this use the whole chat's typical reply
+const ADAPTIVE_MIN_SAMPLES: usize = 5;
+
+/// Where one stretch of chat ends and the next begins
+#[derive(Debug, Clone, PartialEq)]
+pub enum SegmentPolicy {
+    /// After nobody said anything for longer than this
+    Timeout(Duration),
+    /// After a gap `factor` times longer than usual for whoever spoke last
+    /// and whoever spoke next, going by the median gap between the two in
+    /// this chat. Never shorter than `min` or longer than `max`, so quick
+    /// chats split as often as with a timeout and slow ones still split
+    Adaptive {
+        factor: f64,
+        min: Duration,
+        max: Duration,
+    },
+    /// At midnight, UTC
+    Day,
+}
+
+/// Splits sorted conversations into segments: what `format_conversation`
+/// writes as separate conversations, and what `train_test` moves between
+/// sets
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segmenter {
+    pub policy: SegmentPolicy,
+    /// Segments longer than this many messages are cut into pieces
+    pub max_messages: Option<usize>,
+}
+
+impl Default for Segmenter {
+    fn default() -> Segmenter {
+        Segmenter::new(SegmentPolicy::Timeout(Duration::seconds(
+            CONVERSATION_TIMEOUT,
+        )))
+    }
+}
+
+fn median(values: &mut [i64]) -> Option<i64> {
+    values.sort_unstable();
+    values.get(values.len() / 2).copied()
+}
+
+impl Segmenter {
+    pub fn new(policy: SegmentPolicy) -> Segmenter {
+        Segmenter {
+            policy,
+            max_messages: None,
+        }
+    }
+
+    pub fn with_max_messages(mut self, max_messages: usize) -> Segmenter {
+        self.max_messages = Some(max_messages);
+        self
+    }
+
+    // Whether a new segment starts at each message after the first
+    fn breaks<M: Borrow<Message>>(&self, conversation: &[M]) -> Vec<bool> {
+        let gap = |i: usize| {
+            conversation[i]
+                .borrow()
+                .timestamp
+                .signed_duration_since(conversation[i - 1].borrow().timestamp)
+        };
+        let pair = |i: usize| {
+            (
+                conversation[i - 1].borrow().author.as_str(),
+                conversation[i].borrow().author.as_str(),
+            )
+        };
+
+        match &self.policy {
+            SegmentPolicy::Timeout(timeout) => {
+                (1..conversation.len()).map(|i| gap(i) > *timeout).collect()
+            }
+            SegmentPolicy::Day => (1..conversation.len())
+                .map(|i| {
+                    conversation[i].borrow().timestamp.date_naive()
+                        != conversation[i - 1].borrow().timestamp.date_naive()
+                })
+                .collect(),
+            SegmentPolicy::Adaptive { factor, min, max } => {
+                let mut pair_gaps: HashMap<(&str, &str), Vec<i64>> = HashMap::new();
+                let mut all_gaps = Vec::new();
+                for i in 1..conversation.len() {
+                    let seconds = gap(i).num_seconds();
+                    pair_gaps.entry(pair(i)).or_default().push(seconds);
+                    all_gaps.push(seconds);
+                }
+                let typical = median(&mut all_gaps).unwrap_or(0);
+                let thresholds: HashMap<(&str, &str), i64> = pair_gaps
+                    .into_iter()
+                    .map(|(pair, mut gaps)| {
+                        let usual = if gaps.len() < ADAPTIVE_MIN_SAMPLES {
+                            typical
+                        } else {
+                            median(&mut gaps).unwrap_or(typical)
+                        };
+                        let threshold = ((usual as f64) * factor) as i64;
+                        (pair, threshold.clamp(min.num_seconds(), max.num_seconds()))
+                    })
+                    .collect();
+
+                (1..conversation.len())
+                    .map(|i| gap(i).num_seconds() > thresholds[&pair(i)])
+                    .collect()
+            }
+        }
+    }
+
+    /// Splits a sorted conversation into segments, in order
+    pub fn split<'a, M: Borrow<Message>>(&self, conversation: &'a [M]) -> Vec<&'a [M]> {
+        let mut segments = Vec::new();
+        let mut start = 0;
+        for (i, is_break) in self.breaks(conversation).into_iter().enumerate() {
+            let i = i + 1;
+            let too_long = self.max_messages.is_some_and(|max| i - start >= max);
+            if is_break || too_long {
+                segments.push(&conversation[start..i]);
+                start = i;
+            }
+        }
+        if start < conversation.len() {
+            segments.push(&conversation[start..]);
+        }
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn lengths(segmenter: &Segmenter, conversation: &[Message]) -> Vec<usize> {
+        segmenter
+            .split(conversation)
+            .iter()
+            .map(|segment| segment.len())
+            .collect()
+    }
+
+    #[test]
+    fn test_segmenter() {
+        // 23:50 UTC
+        let start = Utc.timestamp_opt(1609545600 - 600, 0).unwrap();
+        let at = |seconds| start + Duration::seconds(seconds);
+        let conversation = vec![
+            Message::text("Alice", "hi", at(0)),
+            Message::text("Bob", "hey", at(60)),
+            // Past midnight
+            Message::text("Alice", "how are you", at(900)),
+            Message::text("Bob", "good", at(960)),
+            Message::text("Alice", "later", at(7200)),
+        ];
+
+        assert_eq!(lengths(&Segmenter::default(), &conversation), vec![2, 2, 1]);
+        assert_eq!(
+            lengths(&Segmenter::new(SegmentPolicy::Day), &conversation),
+            vec![2, 3]
+        );
+        assert_eq!(
+            lengths(&Segmenter::default().with_max_messages(1), &conversation),
+            vec![1, 1, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_adaptive_segmenter() {
+        // Alice always takes about an hour to answer Bob
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let mut conversation = Vec::new();
+        for i in 0..6 {
+            let asked = start + Duration::hours(2 * i);
+            conversation.push(Message::text("Bob", "?", asked));
+            conversation.push(Message::text("Alice", "!", asked + Duration::minutes(60)));
+        }
+
+        let timeout = Segmenter::default();
+        let adaptive = Segmenter::new(SegmentPolicy::Adaptive {
+            factor: ADAPTIVE_GAP_FACTOR,
+            min: Duration::seconds(CONVERSATION_TIMEOUT),
+            max: Duration::days(1),
+        });
+        assert_eq!(timeout.split(&conversation).len(), 12);
+        assert_eq!(adaptive.split(&conversation).len(), 1);
+    }
+}
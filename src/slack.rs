@@ -0,0 +1,366 @@
+use chrono::{DateTime, TimeZone, Utc};
+use multimap::MultiMap;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::{
+    get_json_names, group_by_directory, Archive, Attachment, AttachmentKind, ChatSource,
+    Conversation, Error, Message, MessageKind, Participant, Reaction, Result,
+};
+
+// Message subtypes that are still something a person wrote. Every other
+// subtype is a join, leave, topic change, bot integration...
+const USER_SUBTYPES: &[&str] = &["thread_broadcast", "file_share", "me_message"];
+
+#[derive(Deserialize, Default)]
+struct RawProfile {
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    real_name: String,
+}
+
+#[derive(Deserialize)]
+struct RawUser {
+    id: String,
+    name: String,
+    #[serde(default)]
+    profile: RawProfile,
+}
+
+// channels.json, groups.json and mpims.json key their directories by name,
+// but dms.json only has ids
+#[derive(Deserialize)]
+struct RawChannel {
+    id: String,
+    name: Option<String>,
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RawFile {
+    name: Option<String>,
+    mimetype: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawReaction {
+    name: String,
+    #[serde(default)]
+    users: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RawMessage {
+    r#type: String,
+    subtype: Option<String>,
+    user: Option<String>,
+    username: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    #[serde(default)]
+    files: Vec<RawFile>,
+    #[serde(default)]
+    reactions: Vec<RawReaction>,
+}
+
+fn display_name(user: &RawUser) -> String {
+    if !user.profile.display_name.is_empty() {
+        user.profile.display_name.clone()
+    } else if !user.profile.real_name.is_empty() {
+        user.profile.real_name.clone()
+    } else {
+        user.name.clone()
+    }
+}
+
+fn timestamp(ts: &str) -> Option<DateTime<Utc>> {
+    // "1609459200.000200" -- seconds, then microseconds
+    let mut fields = ts.splitn(2, '.');
+    let seconds: i64 = fields.next()?.parse().ok()?;
+    let micros: u32 = fields.next().unwrap_or("0").parse().ok()?;
+    Utc.timestamp_opt(seconds, micros * 1000).single()
+}
+
+/// Replaces Slack's `<@U123>`, `<#C123|general>` and `<https://...|label>`
+/// markup with readable text, and undoes its HTML escaping
+pub fn resolve_mentions(text: &str, users: &HashMap<String, String>) -> String {
+    let mut resolved = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        resolved.push_str(&rest[..start]);
+
+        let markup = &rest[start + 1..end];
+        let (target, label) = match markup.find('|') {
+            Some(split) => (&markup[..split], Some(&markup[split + 1..])),
+            None => (markup, None),
+        };
+
+        if let Some(user_id) = target.strip_prefix('@') {
+            let name = users
+                .get(user_id)
+                .map(String::as_str)
+                .or(label)
+                .unwrap_or(user_id);
+            resolved.push('@');
+            resolved.push_str(name);
+        } else if let Some(channel_id) = target.strip_prefix('#') {
+            resolved.push('#');
+            resolved.push_str(label.unwrap_or(channel_id));
+        } else if let Some(special) = target.strip_prefix('!') {
+            // <!here>, <!channel>, <!subteam^ID|@team>
+            match label {
+                Some(label) => resolved.push_str(label),
+                None => {
+                    resolved.push('@');
+                    resolved.push_str(special);
+                }
+            }
+        } else {
+            resolved.push_str(label.unwrap_or(target));
+        }
+
+        rest = &rest[end + 1..];
+    }
+    resolved.push_str(rest);
+
+    resolved
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn file_kind(file: &RawFile) -> AttachmentKind {
+    match file.mimetype.as_deref() {
+        Some("image/gif") => AttachmentKind::Gif,
+        Some(mimetype) if mimetype.starts_with("image/") => AttachmentKind::Photo,
+        Some(mimetype) if mimetype.starts_with("video/") => AttachmentKind::Video,
+        Some(mimetype) if mimetype.starts_with("audio/") => AttachmentKind::Audio,
+        _ => AttachmentKind::File,
+    }
+}
+
+fn attachments(files: &[RawFile]) -> Vec<Attachment> {
+    files
+        .iter()
+        .map(|file| Attachment {
+            kind: file_kind(file),
+            uri: file.name.clone().unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(archive: &mut Archive, idx: usize) -> Result<T> {
+    Ok(serde_json::from_str(&archive.read_to_string(idx)?)?)
+}
+
+/// Returns true if the archive is a Slack workspace export
+pub fn is_slack_archive(archive: &Archive) -> bool {
+    let names = archive.file_names();
+    names.iter().any(|name| name == "users.json")
+        && names.iter().any(|name| name == "channels.json")
+}
+
+/// Slack workspace export, zipped or unpacked. Every channel (or DM) is a
+/// directory with one JSON file per day, which are merged into a single
+/// conversation
+pub struct SlackSource {
+    archive: Archive,
+    // User id -> display name
+    users: HashMap<String, String>,
+    // Channel directory -> user ids of its members
+    members: HashMap<String, Vec<String>>,
+    // Channel directory -> file IDs of every day in it
+    channels: MultiMap<String, usize>,
+}
+
+impl SlackSource {
+    pub fn new(mut archive: Archive) -> Result<SlackSource> {
+        let names = get_json_names(&archive)?;
+
+        let users: Vec<RawUser> = match names.get("users.json") {
+            Some(&idx) => read_json(&mut archive, idx)?,
+            None => return Err(Error::Schema(String::from("no users.json in Slack export"))),
+        };
+        let users = users
+            .iter()
+            .map(|user| (user.id.clone(), display_name(user)))
+            .collect();
+
+        let mut members = HashMap::new();
+        for list in ["channels.json", "groups.json", "mpims.json", "dms.json"].iter() {
+            if let Some(&idx) = names.get(*list) {
+                let channels: Vec<RawChannel> = read_json(&mut archive, idx)?;
+                for channel in channels {
+                    let directory = channel.name.unwrap_or(channel.id);
+                    members.insert(directory, channel.members);
+                }
+            }
+        }
+
+        // Day files live one directory down, next to the top-level lists
+        let channels = group_by_directory(&names, |name| Path::new(name).components().count() == 2);
+
+        Ok(SlackSource {
+            archive,
+            users,
+            members,
+            channels,
+        })
+    }
+
+    pub fn open(path: &str) -> Result<SlackSource> {
+        SlackSource::new(Archive::open(path)?)
+    }
+
+    fn user_name(&self, user_id: &str) -> String {
+        match self.users.get(user_id) {
+            Some(name) => name.clone(),
+            None => String::from(user_id),
+        }
+    }
+
+    // Slack names its emoji rather than using the characters, e.g. `:+1:`
+    fn reactions(&self, reactions: &[RawReaction]) -> Vec<Reaction> {
+        reactions
+            .iter()
+            .flat_map(|reaction| {
+                reaction.users.iter().map(move |user| Reaction {
+                    reaction: format!(":{}:", reaction.name),
+                    actor: self.user_name(user),
+                })
+            })
+            .collect()
+    }
+
+    fn parse_day(&self, day: &[RawMessage]) -> Result<Vec<Message>> {
+        day.iter()
+            .filter(|message| message.r#type == "message")
+            .filter(|message| match &message.subtype {
+                Some(subtype) => USER_SUBTYPES.contains(&subtype.as_str()),
+                None => true,
+            })
+            .filter_map(|message| {
+                let author = match (&message.user, &message.username) {
+                    (Some(user), _) => self.user_name(user),
+                    (None, Some(username)) => username.clone(),
+                    (None, None) => return None,
+                };
+
+                let attachments = attachments(&message.files);
+                let kind = match attachments.first() {
+                    Some(attachment) => attachment.kind.message_kind(),
+                    None if message.text.is_empty() => return None,
+                    None => MessageKind::Text,
+                };
+
+                Some(match timestamp(&message.ts) {
+                    Some(timestamp) => Ok(Message {
+                        content: resolve_mentions(&message.text, &self.users),
+                        kind,
+                        attachments,
+                        reactions: self.reactions(&message.reactions),
+                        author,
+                        timestamp,
+                        id: None,
+                        reply_to: None,
+                    }),
+                    None => Err(Error::Schema(format!("bad timestamp {:?}", message.ts))),
+                })
+            })
+            .collect()
+    }
+}
+
+impl ChatSource for SlackSource {
+    fn conversation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.channels.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn read_conversation(&mut self, name: &str) -> Result<Option<Conversation>> {
+        let mut day_idx = match self.channels.get_vec(name) {
+            Some(day_idx) => day_idx.clone(),
+            None => return Ok(None),
+        };
+        day_idx.sort();
+
+        let mut conversation_messages: Vec<Message> = Vec::new();
+        for idx in day_idx {
+            let day: Vec<RawMessage> = read_json(&mut self.archive, idx)?;
+            let mut messages = self.parse_day(&day)?;
+            conversation_messages.append(&mut messages);
+        }
+        if conversation_messages.is_empty() {
+            return Ok(None);
+        }
+
+        conversation_messages.sort_by_key(|a| a.timestamp);
+        println!(
+            "Parsed {} messages from Slack channel {}",
+            conversation_messages.len(),
+            name
+        );
+
+        let mut participants: Vec<Participant> = match self.members.get(name) {
+            Some(members) => members
+                .iter()
+                .map(|member| Participant {
+                    name: self.user_name(member),
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        for message in conversation_messages.iter() {
+            if !participants.iter().any(|p| p.name == message.author) {
+                participants.push(Participant {
+                    name: message.author.clone(),
+                });
+            }
+        }
+
+        Ok(Some(Conversation {
+            name: String::from(name),
+            title: format!("#{}", name),
+            participants,
+            messages: conversation_messages,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_mentions() {
+        let mut users = HashMap::new();
+        users.insert(String::from("U123"), String::from("Alice"));
+
+        assert_eq!(
+            resolve_mentions("hey <@U123> and <@U999|bob>, see <#C1|general>", &users),
+            "hey @Alice and @bob, see #general"
+        );
+        assert_eq!(
+            resolve_mentions("<!here> <https://example.com|docs> &lt;3 &amp;", &users),
+            "@here docs <3 &"
+        );
+        assert_eq!(resolve_mentions("a < b", &users), "a < b");
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let timestamp = timestamp("1609459200.000200").unwrap();
+        assert_eq!(timestamp.timestamp(), 1609459200);
+        assert_eq!(timestamp.timestamp_subsec_micros(), 200);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{Message, Segmenter};
+
+/// How much was said, and by whom, in one conversation or several added up
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Stretches of chat, as the `Segmenter` split them
+    pub segments: usize,
+    pub messages: usize,
+    pub words: usize,
+    /// How many messages each author sent, most first
+    pub authors: Vec<(String, usize)>,
+}
+
+impl Stats {
+    /// `conversation` must be sorted by timestamp
+    pub fn new(conversation: &[Message], segmenter: &Segmenter) -> Stats {
+        let mut stats = Stats {
+            segments: segmenter.split(conversation).len(),
+            messages: conversation.len(),
+            words: conversation
+                .iter()
+                .map(|message| message.content.split_whitespace().count())
+                .sum(),
+            authors: Vec::new(),
+        };
+        for message in conversation {
+            stats.count_author(&message.author, 1);
+        }
+        stats.sort_authors();
+        stats
+    }
+
+    pub fn add(&mut self, other: &Stats) {
+        self.segments += other.segments;
+        self.messages += other.messages;
+        self.words += other.words;
+        for (author, count) in other.authors.iter() {
+            self.count_author(author, *count);
+        }
+        self.sort_authors();
+    }
+
+    fn count_author(&mut self, author: &str, count: usize) {
+        match self.authors.iter_mut().find(|(name, _)| name == author) {
+            Some((_, total)) => *total += count,
+            None => self.authors.push((String::from(author), count)),
+        }
+    }
+
+    fn sort_authors(&mut self) {
+        let first_seen: HashMap<String, usize> = self
+            .authors
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name.clone(), i))
+            .collect();
+        self.authors
+            .sort_by_key(|(name, count)| (std::cmp::Reverse(*count), first_seen[name]));
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} messages, {} words in {} conversations",
+            self.messages, self.words, self.segments
+        )?;
+        for (author, count) in self.authors.iter() {
+            writeln!(
+                f,
+                "  {}: {} ({:.1}%)",
+                author,
+                count,
+                100.0 * *count as f64 / self.messages as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone, Utc};
+
+    #[test]
+    fn test_stats() {
+        let start = Utc.timestamp_opt(1609459200, 0).unwrap();
+        let conversation = vec![
+            Message::text("Alice", "hi there", start),
+            Message::text("Bob", "hey", start + Duration::seconds(5)),
+            Message::text("Bob", "what's up", start + Duration::seconds(6)),
+            Message::text("Alice", "later", start + Duration::hours(1)),
+        ];
+
+        let segmenter = Segmenter::default();
+        let mut stats = Stats::new(&conversation, &segmenter);
+        assert_eq!(stats.segments, 2);
+        assert_eq!(stats.words, 6);
+        assert_eq!(
+            stats.authors,
+            vec![(String::from("Alice"), 2), (String::from("Bob"), 2)]
+        );
+
+        stats.add(&Stats::new(&conversation[1..2], &segmenter));
+        assert_eq!(stats.messages, 5);
+        assert_eq!(stats.authors[0], (String::from("Bob"), 3));
+    }
+}
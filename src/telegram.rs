@@ -0,0 +1,358 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::Read;
+
+use crate::{
+    resolve_replies, Archive, Attachment, AttachmentKind, ChatSource, Conversation, Error, Message,
+    MessageKind, Participant, ReplyTo, Result,
+};
+
+#[derive(Deserialize)]
+struct RawEntity {
+    text: String,
+    href: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTextPart {
+    Plain(String),
+    Entity(RawEntity),
+}
+
+// Plain messages are a string, but as soon as there's any formatting in it
+// Telegram switches to an array of strings and entity objects
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawText {
+    Plain(String),
+    Parts(Vec<RawTextPart>),
+}
+
+impl Default for RawText {
+    fn default() -> RawText {
+        RawText::Plain(String::new())
+    }
+}
+
+#[derive(Deserialize)]
+struct RawMessage {
+    id: i64,
+    r#type: String,
+    date: String,
+    date_unixtime: Option<String>,
+    from: Option<String>,
+    #[serde(default)]
+    text: RawText,
+    photo: Option<String>,
+    file: Option<String>,
+    media_type: Option<String>,
+    reply_to_message_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RawChat {
+    name: Option<String>,
+    id: i64,
+    #[serde(default)]
+    messages: Vec<RawMessage>,
+}
+
+#[derive(Deserialize)]
+struct RawChatList {
+    list: Vec<RawChat>,
+}
+
+// A full account export nests every chat under `chats`, but exporting a
+// single chat writes that chat at the top level instead
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawExport {
+    Account {
+        chats: RawChatList,
+        left_chats: Option<RawChatList>,
+    },
+    Chat(RawChat),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawExportHeader {
+    Account {
+        #[serde(rename = "chats")]
+        _chats: IgnoredAny,
+    },
+    Chat {
+        #[serde(rename = "type")]
+        _type: IgnoredAny,
+        #[serde(rename = "messages")]
+        _messages: IgnoredAny,
+    },
+}
+
+/// Returns true if the JSON is a Telegram Desktop export, of a whole account
+/// or of one chat
+pub fn is_telegram_export(json_data: &str) -> bool {
+    serde_json::from_str::<RawExportHeader>(json_data).is_ok()
+}
+
+/// Flattens Telegram's text entities (bold, links, mentions...) into plain
+/// text. Links whose text differs from their target keep the target
+fn flatten_text(text: &RawText) -> String {
+    match text {
+        RawText::Plain(text) => text.clone(),
+        RawText::Parts(parts) => parts
+            .iter()
+            .map(|part| match part {
+                RawTextPart::Plain(text) => text.clone(),
+                RawTextPart::Entity(RawEntity {
+                    text,
+                    href: Some(href),
+                }) if href != text => format!("{} ({})", text, href),
+                RawTextPart::Entity(entity) => entity.text.clone(),
+            })
+            .collect(),
+    }
+}
+
+fn attachment(message: &RawMessage) -> Option<Attachment> {
+    if let Some(photo) = &message.photo {
+        return Some(Attachment {
+            kind: AttachmentKind::Photo,
+            uri: photo.clone(),
+        });
+    }
+
+    let file = message.file.as_ref()?;
+    let kind = match message.media_type.as_deref() {
+        Some("sticker") => AttachmentKind::Sticker,
+        Some("animation") => AttachmentKind::Gif,
+        Some("video_file") | Some("video_message") => AttachmentKind::Video,
+        Some("voice_message") | Some("audio_file") => AttachmentKind::Audio,
+        _ => AttachmentKind::File,
+    };
+    Some(Attachment {
+        kind,
+        uri: file.clone(),
+    })
+}
+
+fn timestamp(message: &RawMessage) -> Option<DateTime<Utc>> {
+    if let Some(unixtime) = &message.date_unixtime {
+        return Utc.timestamp_opt(unixtime.parse().ok()?, 0).single();
+    }
+
+    // Older exports only have the local time of the exporting machine
+    let naive = NaiveDateTime::parse_from_str(&message.date, "%Y-%m-%dT%H:%M:%S").ok()?;
+    Some(Utc.from_utc_datetime(&naive))
+}
+
+fn chat_title(chat: &RawChat) -> String {
+    match &chat.name {
+        Some(name) => name.clone(),
+        // Saved messages and chats with deleted accounts have no name
+        None => format!("Telegram chat {}", chat.id),
+    }
+}
+
+// Chat titles aren't unique and can contain anything, so name conversations
+// after the title and the chat's id, the way Facebook names its threads
+fn chat_name(chat: &RawChat) -> String {
+    match &chat.name {
+        Some(name) => {
+            let name: String = name
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect();
+            format!("{}_{}", name.to_lowercase(), chat.id)
+        }
+        None => format!("chat_{}", chat.id),
+    }
+}
+
+fn parse_chat(chat: &RawChat) -> Result<(Vec<Participant>, Vec<Message>)> {
+    let mut participants: Vec<Participant> = Vec::new();
+    let mut messages: Vec<Message> = Vec::new();
+
+    for message in chat.messages.iter() {
+        // Service messages are joins, pins, calls and the like
+        if message.r#type != "message" {
+            continue;
+        }
+        let timestamp = match timestamp(message) {
+            Some(timestamp) => timestamp,
+            None => return Err(Error::Schema(format!("bad date {:?}", message.date))),
+        };
+        let author = match &message.from {
+            Some(from) => from.clone(),
+            None => String::from("Deleted Account"),
+        };
+
+        if !participants.iter().any(|p| p.name == author) {
+            participants.push(Participant {
+                name: author.clone(),
+            });
+        }
+
+        let content = flatten_text(&message.text);
+        let attachments: Vec<Attachment> = attachment(message).into_iter().collect();
+        let kind = match attachments.first() {
+            Some(attachment) => attachment.kind.message_kind(),
+            None if content.is_empty() => continue,
+            None => MessageKind::Text,
+        };
+
+        messages.push(Message {
+            content,
+            kind,
+            attachments,
+            reactions: Vec::new(),
+            author,
+            timestamp,
+            id: Some(message.id.to_string()),
+            reply_to: message
+                .reply_to_message_id
+                .map(|id| ReplyTo::Id(id.to_string())),
+        });
+    }
+
+    Ok((participants, messages))
+}
+
+/// Telegram Desktop's JSON export (`result.json`), either of a whole account
+/// or of a single chat
+pub struct TelegramSource {
+    chats: Vec<RawChat>,
+}
+
+impl TelegramSource {
+    pub fn from_json(json_data: &str) -> Result<TelegramSource> {
+        let chats = match serde_json::from_str(json_data)? {
+            RawExport::Account { chats, left_chats } => {
+                let mut chats = chats.list;
+                if let Some(mut left_chats) = left_chats {
+                    chats.append(&mut left_chats.list);
+                }
+                chats
+            }
+            RawExport::Chat(chat) => vec![chat],
+        };
+
+        Ok(TelegramSource { chats })
+    }
+
+    pub fn open(path: &str) -> Result<TelegramSource> {
+        let mut json_data = String::new();
+        File::open(path)?.read_to_string(&mut json_data)?;
+        TelegramSource::from_json(&json_data)
+    }
+
+    /// Reads `result.json` out of a zipped or unpacked export directory
+    pub fn from_archive(archive: &mut Archive) -> Result<TelegramSource> {
+        let idx = archive
+            .file_names()
+            .iter()
+            .position(|name| name.ends_with("result.json"))
+            .ok_or_else(|| Error::Schema(String::from("no result.json in Telegram export")))?;
+
+        TelegramSource::from_json(&archive.read_to_string(idx)?)
+    }
+}
+
+/// Returns true if the archive holds a Telegram Desktop export
+pub fn is_telegram_archive(archive: &Archive) -> bool {
+    archive
+        .file_names()
+        .iter()
+        .any(|name| name.ends_with("result.json"))
+}
+
+impl ChatSource for TelegramSource {
+    fn conversation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.chats.iter().map(chat_name).collect();
+        names.sort();
+        names
+    }
+
+    fn read_conversation(&mut self, name: &str) -> Result<Option<Conversation>> {
+        let chat = match self.chats.iter().find(|chat| chat_name(chat) == name) {
+            Some(chat) => chat,
+            None => return Ok(None),
+        };
+        let (participants, mut messages) = parse_chat(chat)?;
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        messages.sort_by_key(|a| a.timestamp);
+        resolve_replies(&mut messages);
+        println!(
+            "Parsed {} messages from Telegram chat {}",
+            messages.len(),
+            name
+        );
+
+        Ok(Some(Conversation {
+            name: String::from(name),
+            title: chat_title(chat),
+            participants,
+            messages,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_telegram_account_export() {
+        let json_data = r#"{
+            "chats": {"list": [{
+                "name": "Friends", "type": "private_group", "id": 42,
+                "messages": [
+                    {"id": 1, "type": "service", "date": "2021-01-01T00:00:00",
+                     "actor": "Alice", "action": "create_group"},
+                    {"id": 2, "type": "message", "date": "2021-01-01T00:00:05",
+                     "date_unixtime": "1609459205", "from": "Alice",
+                     "text": ["see ", {"type": "bold", "text": "this"}, " ",
+                              {"type": "text_link", "text": "link", "href": "https://example.com"}]},
+                    {"id": 3, "type": "message", "date": "2021-01-01T00:00:09",
+                     "from": "Bob", "text": "", "photo": "photos/photo_1.jpg"}
+                ]
+            }]}
+        }"#;
+        let mut source = TelegramSource::from_json(json_data).unwrap();
+        assert_eq!(source.conversation_names(), vec!["friends_42"]);
+
+        let conversation = source.read_conversation("friends_42").unwrap().unwrap();
+        assert_eq!(conversation.title, "Friends");
+        assert_eq!(conversation.participants.len(), 2);
+        assert_eq!(
+            conversation.messages[0].content,
+            "see this link (https://example.com)"
+        );
+        assert_eq!(conversation.messages[0].timestamp.timestamp(), 1609459205);
+        assert_eq!(conversation.messages[1].kind, MessageKind::Photo);
+        assert_eq!(
+            conversation.messages[1].render_content(),
+            "PHOTOS: -photos/photo_1.jpg"
+        );
+    }
+
+    #[test]
+    fn test_telegram_single_chat_export() {
+        let json_data = r#"{"name": null, "type": "saved_messages", "id": 7, "messages": [
+            {"id": 1, "type": "message", "date": "2021-01-01T00:00:05", "from": null, "text": "hi"}
+        ]}"#;
+        assert!(is_telegram_export(json_data));
+        let mut source = TelegramSource::from_json(json_data).unwrap();
+        let conversation = source.read_conversation("chat_7").unwrap().unwrap();
+        assert_eq!(conversation.messages[0].author, "Deleted Account");
+
+        assert!(!is_telegram_export(r#"{"messages": []}"#));
+        assert!(!is_telegram_export(r#"[{"type": "message"}]"#));
+    }
+}
@@ -0,0 +1,427 @@
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::{Error, Result};
+
+/// GPT-2's mapping from bytes to the printable characters its vocabulary is
+/// written in: printable Latin-1 maps to itself, everything else to U+0100
+/// onwards
+pub(crate) fn byte_encoder() -> Vec<char> {
+    let mut encoder = vec!['\0'; 256];
+    let mut next = 256;
+    for byte in 0..=255u8 {
+        encoder[byte as usize] = match byte {
+            b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF => byte as char,
+            _ => {
+                next += 1;
+                std::char::from_u32(next - 1).unwrap()
+            }
+        };
+    }
+    encoder
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CharClass {
+    Letter,
+    Number,
+    Space,
+    Other,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_alphabetic() {
+        CharClass::Letter
+    } else if c.is_numeric() {
+        CharClass::Number
+    } else if c.is_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Other
+    }
+}
+
+const CONTRACTIONS: &[&str] = &["'s", "'t", "'re", "'ve", "'m", "'ll", "'d"];
+
+/// Splits text into the words GPT-2 runs BPE on separately, like its
+/// `'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+`
+fn pretokenize(text: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let offset = |i: usize| chars.get(i).map_or(text.len(), |(offset, _)| *offset);
+    let run_end = |mut i: usize, class: CharClass| {
+        while i < chars.len() && char_class(chars[i].1) == class {
+            i += 1;
+        }
+        i
+    };
+
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let rest = &text[offset(i)..];
+        let end = if let Some(contraction) = CONTRACTIONS.iter().find(|c| rest.starts_with(*c)) {
+            i + contraction.chars().count()
+        } else {
+            let c = chars[i].1;
+            let next = chars.get(i + 1).map(|(_, c)| char_class(*c));
+            match (c, char_class(c), next) {
+                // A space goes with the word after it
+                (' ', _, Some(class)) if class != CharClass::Space => run_end(i + 1, class),
+                (_, CharClass::Space, _) => {
+                    let end = run_end(i, CharClass::Space);
+                    // ...but only the last one, if there are several
+                    if end < chars.len() && end - i > 1 {
+                        end - 1
+                    } else {
+                        end
+                    }
+                }
+                (_, class, _) => run_end(i, class),
+            }
+        };
+        words.push(&text[offset(i)..offset(end)]);
+        i = end;
+    }
+    words
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMerge {
+    Joined(String),
+    Pair(String, String),
+}
+
+#[derive(Deserialize)]
+struct RawModel {
+    r#type: Option<String>,
+    vocab: HashMap<String, u32>,
+    merges: Vec<RawMerge>,
+}
+
+#[derive(Deserialize)]
+struct RawAddedToken {
+    id: u32,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct RawTokenizer {
+    model: RawModel,
+    pre_tokenizer: Option<serde_json::Value>,
+    #[serde(default)]
+    added_tokens: Vec<RawAddedToken>,
+}
+
+// GPT-2's own files don't say which tokens are special, but this one is
+const GPT2_END_OF_TEXT: &str = "<|endoftext|>";
+
+enum Piece<'t> {
+    Added(u32),
+    Text(&'t str),
+}
+
+fn parse_merge(line: &str) -> Result<(String, String)> {
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(a), Some(b), None) => Ok((String::from(a), String::from(b))),
+        _ => Err(Error::Schema(format!("bad merge {:?}", line))),
+    }
+}
+
+/// A byte-level BPE tokenizer, like GPT-2's and most models' on the
+/// HuggingFace hub, for counting tokens without a network connection
+pub struct Tokenizer {
+    vocab: HashMap<String, u32>,
+    ranks: HashMap<(String, String), usize>,
+    byte_encoder: Vec<char>,
+    // Longest first, so a shorter one can't cut a longer one short
+    added: Vec<(String, u32)>,
+    // Chats repeat the same words over and over
+    cache: RefCell<HashMap<String, Vec<u32>>>,
+}
+
+impl Tokenizer {
+    /// `merges` in order of priority, highest first
+    pub fn new(vocab: HashMap<String, u32>, merges: Vec<(String, String)>) -> Tokenizer {
+        Tokenizer {
+            vocab,
+            ranks: merges
+                .into_iter()
+                .enumerate()
+                .map(|(rank, pair)| (pair, rank))
+                .collect(),
+            byte_encoder: byte_encoder(),
+            added: Vec::new(),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Tokens like `<|endoftext|>` that are always one token, wherever they
+    /// turn up, instead of going through BPE
+    pub fn with_added_tokens<I: IntoIterator<Item = (String, u32)>>(
+        mut self,
+        tokens: I,
+    ) -> Tokenizer {
+        self.added.extend(tokens);
+        self.added
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        self.added.dedup_by(|(a, _), (b, _)| a == b);
+        self
+    }
+
+    /// From the contents of GPT-2's `vocab.json` and `merges.txt`
+    pub fn from_gpt2(vocab_json: &str, merges_txt: &str) -> Result<Tokenizer> {
+        let merges = merges_txt
+            .lines()
+            .filter(|line| !line.starts_with("#version") && !line.is_empty())
+            .map(parse_merge)
+            .collect::<Result<Vec<_>>>()?;
+        let vocab: HashMap<String, u32> = serde_json::from_str(vocab_json)?;
+        let end_of_text = vocab
+            .get(GPT2_END_OF_TEXT)
+            .map(|&id| (String::from(GPT2_END_OF_TEXT), id));
+        Ok(Tokenizer::new(vocab, merges).with_added_tokens(end_of_text))
+    }
+
+    /// From the contents of a HuggingFace `tokenizer.json`
+    pub fn from_tokenizer_json(json_data: &str) -> Result<Tokenizer> {
+        let raw: RawTokenizer = serde_json::from_str(json_data)?;
+        if raw.model.r#type.as_deref().is_some_and(|t| t != "BPE") {
+            return Err(Error::Schema(String::from(
+                "only BPE tokenizers are supported",
+            )));
+        }
+        let byte_level = raw
+            .pre_tokenizer
+            .is_some_and(|pre_tokenizer| pre_tokenizer.to_string().contains("ByteLevel"));
+        if !byte_level {
+            return Err(Error::Schema(String::from(
+                "only byte-level tokenizers are supported",
+            )));
+        }
+
+        let merges = raw
+            .model
+            .merges
+            .into_iter()
+            .map(|merge| match merge {
+                RawMerge::Joined(line) => parse_merge(&line),
+                RawMerge::Pair(a, b) => Ok((a, b)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let added = raw
+            .added_tokens
+            .into_iter()
+            .map(|token| (token.content, token.id));
+        Ok(Tokenizer::new(raw.model.vocab, merges).with_added_tokens(added))
+    }
+
+    /// Opens a `tokenizer.json`, GPT-2's `vocab.json` with `merges.txt` next
+    /// to it, or a directory holding either
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Tokenizer> {
+        let path = path.as_ref();
+        let (dir, file) = if path.is_dir() {
+            let file = if path.join("tokenizer.json").exists() {
+                "tokenizer.json"
+            } else {
+                "vocab.json"
+            };
+            (path, file)
+        } else {
+            let file = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            (path.parent().unwrap_or_else(|| Path::new("")), file)
+        };
+
+        if file == "vocab.json" || file == "merges.txt" {
+            Tokenizer::from_gpt2(
+                &read_to_string(dir.join("vocab.json"))?,
+                &read_to_string(dir.join("merges.txt"))?,
+            )
+        } else {
+            Tokenizer::from_tokenizer_json(&read_to_string(dir.join(file))?)
+        }
+    }
+
+    fn encode_word(&self, word: &str) -> Vec<u32> {
+        let mut symbols: Vec<String> = word
+            .bytes()
+            .map(|byte| self.byte_encoder[byte as usize].to_string())
+            .collect();
+
+        // Merge the best ranked pair, wherever it is, until none are left
+        loop {
+            let best = symbols
+                .windows(2)
+                .filter_map(|pair| self.ranks.get(&(pair[0].clone(), pair[1].clone())))
+                .min();
+            let best = match best {
+                Some(&rank) => rank,
+                None => break,
+            };
+
+            let mut merged = Vec::with_capacity(symbols.len());
+            let mut i = 0;
+            while i < symbols.len() {
+                if i + 1 < symbols.len()
+                    && self
+                        .ranks
+                        .get(&(symbols[i].clone(), symbols[i + 1].clone()))
+                        == Some(&best)
+                {
+                    merged.push(format!("{}{}", symbols[i], symbols[i + 1]));
+                    i += 2;
+                } else {
+                    merged.push(symbols[i].clone());
+                    i += 1;
+                }
+            }
+            symbols = merged;
+        }
+
+        // Every byte is in a byte-level vocabulary, so this only drops
+        // anything if the files don't match
+        symbols
+            .iter()
+            .filter_map(|symbol| self.vocab.get(symbol).copied())
+            .collect()
+    }
+
+    /// Cuts `text` around the added tokens, before anything is pretokenized
+    fn split_added<'t>(&self, text: &'t str) -> Vec<Piece<'t>> {
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < text.len() {
+            let rest = &text[i..];
+            match self
+                .added
+                .iter()
+                .find(|(content, _)| rest.starts_with(content.as_str()))
+            {
+                Some((content, id)) => {
+                    if start < i {
+                        pieces.push(Piece::Text(&text[start..i]));
+                    }
+                    pieces.push(Piece::Added(*id));
+                    i += content.len();
+                    start = i;
+                }
+                None => i += rest.chars().next().unwrap().len_utf8(),
+            }
+        }
+        if start < text.len() {
+            pieces.push(Piece::Text(&text[start..]));
+        }
+        pieces
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut ids = Vec::new();
+        for piece in self.split_added(text) {
+            let text = match piece {
+                Piece::Added(id) => {
+                    ids.push(id);
+                    continue;
+                }
+                Piece::Text(text) => text,
+            };
+            for word in pretokenize(text) {
+                let mut cache = self.cache.borrow_mut();
+                let word_ids = cache
+                    .entry(String::from(word))
+                    .or_insert_with(|| self.encode_word(word));
+                ids.extend_from_slice(word_ids);
+            }
+        }
+        ids
+    }
+
+    /// How many tokens `text` takes up
+    pub fn count(&self, text: &str) -> usize {
+        self.split_added(text)
+            .into_iter()
+            .map(|piece| match piece {
+                Piece::Added(_) => 1,
+                Piece::Text(text) => pretokenize(text)
+                    .into_iter()
+                    .map(|word| {
+                        let mut cache = self.cache.borrow_mut();
+                        cache
+                            .entry(String::from(word))
+                            .or_insert_with(|| self.encode_word(word))
+                            .len()
+                    })
+                    .sum(),
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretokenize() {
+        assert_eq!(
+            pretokenize("Hello world's  3.5 :)\n\nbye  "),
+            vec!["Hello", " world", "'s", " ", " 3", ".", "5", " :)", "\n", "\n", "bye", "  "]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let vocab_json =
+            r#"{"h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "he": 5, "ll": 6, "hell": 7, "Ġh": 8}"#;
+        let merges_txt = "#version: 0.2\nh e\nl l\nhe ll\nĠ h\n";
+        let tokenizer = Tokenizer::from_gpt2(vocab_json, merges_txt).unwrap();
+
+        assert_eq!(tokenizer.encode("hello"), vec![7, 3]);
+        // "he" outranks "Ġh"
+        assert_eq!(tokenizer.encode("hello hello"), vec![7, 3, 4, 7, 3]);
+        assert_eq!(tokenizer.count("hello hello"), 5);
+
+        let tokenizer_json = format!(
+            r#"{{"model": {{"type": "BPE", "vocab": {}, "merges": [["h", "e"], ["l", "l"], ["he", "ll"]]}},
+                "pre_tokenizer": {{"type": "ByteLevel", "add_prefix_space": false}}}}"#,
+            vocab_json
+        );
+        let tokenizer = Tokenizer::from_tokenizer_json(&tokenizer_json).unwrap();
+        assert_eq!(tokenizer.encode("hello"), vec![7, 3]);
+        assert!(Tokenizer::from_tokenizer_json(
+            r#"{"model": {"type": "Unigram", "vocab": {}, "merges": []}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_added_tokens() {
+        let tokenizer_json = r#"{
+            "added_tokens": [
+                {"id": 9, "content": "<|endoftext|>", "special": true},
+                {"id": 10, "content": "|EOM|", "special": false}
+            ],
+            "model": {"type": "BPE", "vocab": {"h": 0, "e": 1, "l": 2, "o": 3, "he": 5,
+                      "ll": 6, "hell": 7}, "merges": ["h e", "l l", "he ll"]},
+            "pre_tokenizer": {"type": "ByteLevel"}
+        }"#;
+        let tokenizer = Tokenizer::from_tokenizer_json(tokenizer_json).unwrap();
+
+        assert_eq!(tokenizer.count("<|endoftext|>"), 1);
+        assert_eq!(
+            tokenizer.encode("hello|EOM|hello<|endoftext|>"),
+            vec![7, 3, 10, 7, 3, 9]
+        );
+
+        // GPT-2's vocab.json has it as an ordinary entry
+        let tokenizer = Tokenizer::from_gpt2(r#"{"h": 0, "<|endoftext|>": 1}"#, "").unwrap();
+        assert_eq!(tokenizer.encode("h<|endoftext|>"), vec![0, 1]);
+    }
+}
@@ -0,0 +1,518 @@
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::{
+    Archive, Attachment, AttachmentKind, ChatSource, Conversation, Message, MessageKind,
+    Participant, Result,
+};
+
+// WhatsApp sprinkles these through iOS exports and newer Android ones
+const LEFT_TO_RIGHT_MARK: char = '\u{200e}';
+const NARROW_NO_BREAK_SPACE: char = '\u{202f}';
+const NO_BREAK_SPACE: char = '\u{a0}';
+
+// Lines that carry a timestamp but weren't written by anyone. Android exports
+// leave the author off these entirely, but iOS attributes them to the group
+const SYSTEM_PHRASES: &[&str] = &[
+    "Messages and calls are end-to-end encrypted",
+    "Messages to this group are now secured with end-to-end encryption",
+    "Messages to this chat and calls are now secured with end-to-end encryption",
+    "created group",
+    "created this group",
+    "changed the subject",
+    "changed this group's icon",
+    "changed the group description",
+    "deleted this group's icon",
+    "security code changed",
+    "joined using this group's invite link",
+    "changed their phone number",
+];
+
+/// Order of the day, month and year fields in the export's dates, which
+/// depends on the locale of the phone that exported the chat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateOrder {
+    DayMonthYear,
+    MonthDayYear,
+    YearMonthDay,
+}
+
+// A line that starts a new message, before we know how to read its date
+struct RawLine<'a> {
+    date: [u32; 3],
+    date_digits: [usize; 3],
+    time: (u32, u32, u32),
+    author: Option<&'a str>,
+    text: String,
+}
+
+fn parse_date(date: &str) -> Option<([u32; 3], [usize; 3])> {
+    let fields: Vec<&str> = date.split(['/', '.', '-']).collect();
+    if fields.len() != 3 {
+        return None;
+    }
+
+    let mut values = [0; 3];
+    let mut digits = [0; 3];
+    for (i, field) in fields.iter().enumerate() {
+        if field.is_empty() || !field.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        values[i] = field.parse().ok()?;
+        digits[i] = field.len();
+    }
+
+    Some((values, digits))
+}
+
+fn parse_time(time: &str) -> Option<(u32, u32, u32)> {
+    let time = time.trim().to_lowercase().replace('.', "");
+    let (time, offset) = if let Some(time) = time.strip_suffix("am") {
+        (time.trim(), Some(0))
+    } else if let Some(time) = time.strip_suffix("pm") {
+        (time.trim(), Some(12))
+    } else {
+        (time.as_str(), None)
+    };
+
+    let fields: Vec<u32> = time
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    let (hour, minute, second) = match fields.as_slice() {
+        [hour, minute] => (*hour, *minute, 0),
+        [hour, minute, second] => (*hour, *minute, *second),
+        _ => return None,
+    };
+
+    // 12 AM is midnight, and 12 PM is noon
+    let hour = match offset {
+        Some(offset) if hour <= 12 => hour % 12 + offset,
+        Some(_) => return None,
+        None => hour,
+    };
+
+    Some((hour, minute, second))
+}
+
+fn parse_header(line: &str) -> Option<RawLine<'_>> {
+    let line = line.trim_start_matches(LEFT_TO_RIGHT_MARK);
+
+    // iOS:     [31/12/2020, 23:59:59] Name: text
+    // Android: 12/31/20, 11:59 PM - Name: text
+    let (datetime, rest) = if let Some(line) = line.strip_prefix('[') {
+        let end = line.find("] ")?;
+        (&line[..end], &line[end + 2..])
+    } else {
+        let end = line.find(" - ")?;
+        (&line[..end], &line[end + 3..])
+    };
+
+    let datetime = datetime.replace([NARROW_NO_BREAK_SPACE, NO_BREAK_SPACE], " ");
+    let split = datetime.find(", ").or_else(|| datetime.find(' '))?;
+    let (date, date_digits) = parse_date(&datetime[..split])?;
+    let time = parse_time(datetime[split + 1..].trim_start_matches(','))?;
+
+    let (author, text) = match rest.find(": ") {
+        Some(end) => (Some(&rest[..end]), &rest[end + 2..]),
+        None => (None, rest),
+    };
+
+    Some(RawLine {
+        date,
+        date_digits,
+        time,
+        author,
+        text: String::from(text),
+    })
+}
+
+fn detect_date_order(lines: &[RawLine]) -> DateOrder {
+    if lines.iter().any(|line| line.date_digits[0] == 4) {
+        DateOrder::YearMonthDay
+    } else if lines.iter().any(|line| line.date[0] > 12) {
+        DateOrder::DayMonthYear
+    } else if lines.iter().any(|line| line.date[1] > 12) {
+        DateOrder::MonthDayYear
+    } else {
+        // Nothing to tell them apart, so assume the US default
+        DateOrder::MonthDayYear
+    }
+}
+
+fn to_timestamp(line: &RawLine, date_order: DateOrder) -> Option<DateTime<Utc>> {
+    let [a, b, c] = line.date;
+    let (year, month, day) = match date_order {
+        DateOrder::DayMonthYear => (c, b, a),
+        DateOrder::MonthDayYear => (c, a, b),
+        DateOrder::YearMonthDay => (a, b, c),
+    };
+    let year = if year < 100 { year + 2000 } else { year };
+
+    // Exports don't record a timezone, so treat the phone's local time as UTC
+    let (hour, minute, second) = line.time;
+    let naive =
+        NaiveDate::from_ymd_opt(year as i32, month, day)?.and_hms_opt(hour, minute, second)?;
+    Some(Utc.from_utc_datetime(&naive))
+}
+
+fn is_system_text(text: &str) -> bool {
+    let text = text.trim_start_matches(LEFT_TO_RIGHT_MARK);
+    SYSTEM_PHRASES.iter().any(|phrase| text.contains(phrase))
+}
+
+// Only lines without an author, or with their text marked the way iOS marks
+// system lines, so people can still say "I created this group"
+fn is_system_line(line: &RawLine) -> bool {
+    match line.author {
+        None => true,
+        // An Android system line with ": " in it, like a new subject
+        Some(author) if is_system_text(author) => true,
+        Some(_) => line.text.starts_with(LEFT_TO_RIGHT_MARK) && is_system_text(&line.text),
+    }
+}
+
+fn media_kind(file_name: &str) -> AttachmentKind {
+    let upper = file_name.to_uppercase();
+    if upper.contains("PHOTO") || upper.starts_with("IMG-") {
+        AttachmentKind::Photo
+    } else if upper.contains("VIDEO") || upper.starts_with("VID-") {
+        AttachmentKind::Video
+    } else if upper.contains("GIF") {
+        AttachmentKind::Gif
+    } else if upper.contains("STICKER") || upper.starts_with("STK-") {
+        AttachmentKind::Sticker
+    } else if upper.contains("AUDIO") || upper.starts_with("PTT-") || upper.starts_with("AUD-") {
+        AttachmentKind::Audio
+    } else {
+        AttachmentKind::File
+    }
+}
+
+// Media is either attached by file name, or left out of the export with only
+// a note of what kind it was
+fn parse_media(text: &str) -> Option<(MessageKind, Vec<Attachment>)> {
+    let text = text.trim_start_matches(LEFT_TO_RIGHT_MARK).trim_end();
+
+    let file_name = text
+        .strip_prefix("<attached: ")
+        .and_then(|text| text.strip_suffix('>'))
+        .or_else(|| text.strip_suffix(" (file attached)"));
+    if let Some(file_name) = file_name {
+        let kind = media_kind(file_name);
+        let attachment = Attachment {
+            kind,
+            uri: String::from(file_name),
+        };
+        return Some((kind.message_kind(), vec![attachment]));
+    }
+
+    let kind = match text {
+        // Android doesn't say what kind of media it was
+        "<Media omitted>" | "document omitted" => MessageKind::File,
+        "image omitted" => MessageKind::Photo,
+        "video omitted" => MessageKind::Video,
+        "GIF omitted" => MessageKind::Gif,
+        "sticker omitted" => MessageKind::Sticker,
+        "audio omitted" => MessageKind::Audio,
+        _ => return None,
+    };
+    Some((kind, Vec::new()))
+}
+
+/// Parses the text of a WhatsApp "Export chat" file. If `date_order` is
+/// `None`, it's guessed from the dates in the file
+pub fn parse_chat(text: &str, date_order: Option<DateOrder>) -> (Vec<Participant>, Vec<Message>) {
+    let mut lines: Vec<RawLine> = Vec::new();
+    for line in text.lines() {
+        match parse_header(line) {
+            Some(raw_line) => lines.push(raw_line),
+            None => {
+                // Anything that doesn't start with a timestamp continues the
+                // message above it
+                if let Some(last) = lines.last_mut() {
+                    last.text.push('\n');
+                    last.text.push_str(line);
+                }
+            }
+        }
+    }
+
+    let date_order = date_order.unwrap_or_else(|| detect_date_order(&lines));
+
+    let mut participants: Vec<Participant> = Vec::new();
+    let mut messages: Vec<Message> = Vec::new();
+    for line in lines.iter() {
+        let author = match line.author {
+            Some(author) if !is_system_line(line) => author.trim_start_matches(LEFT_TO_RIGHT_MARK),
+            _ => continue,
+        };
+        let timestamp = match to_timestamp(line, date_order) {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+
+        if !participants.iter().any(|p| p.name == author) {
+            participants.push(Participant {
+                name: String::from(author),
+            });
+        }
+
+        messages.push(match parse_media(&line.text) {
+            Some((kind, attachments)) => Message {
+                content: String::new(),
+                kind,
+                attachments,
+                reactions: Vec::new(),
+                author: String::from(author),
+                timestamp,
+                id: None,
+                reply_to: None,
+            },
+            None => Message::text(
+                author,
+                &line.text.replace(LEFT_TO_RIGHT_MARK, ""),
+                timestamp,
+            ),
+        });
+    }
+
+    (participants, messages)
+}
+
+/// Names a chat after its file, unless that's iOS's `_chat.txt`, which every
+/// export is called. Those are named after the people in them instead, so
+/// exports of the same chat get the same name however the zip was called
+fn chat_name(stem: Option<&str>, text: &str, fallback: &str) -> String {
+    match stem {
+        Some("_chat") | None => {
+            let (participants, _) = parse_chat(text, None);
+            let mut names: Vec<&str> = participants
+                .iter()
+                .map(|participant| participant.name.as_str())
+                .collect();
+            if names.is_empty() {
+                return String::from(fallback);
+            }
+            names.sort();
+            format!("WhatsApp Chat - {}", names.join(", "))
+        }
+        Some(stem) => String::from(stem),
+    }
+}
+
+fn chat_title(name: &str) -> String {
+    let title = name
+        .trim_start_matches("WhatsApp Chat with ")
+        .trim_start_matches("WhatsApp Chat - ");
+    String::from(title)
+}
+
+/// Returns true if the archive looks like a WhatsApp export rather than a
+/// Facebook one
+pub fn is_whatsapp_archive(archive: &Archive) -> bool {
+    archive.file_names().iter().any(|name| {
+        let file_name = name.rsplit('/').next().unwrap();
+        file_name == "_chat.txt" || file_name.starts_with("WhatsApp Chat")
+    })
+}
+
+/// One or more WhatsApp "Export chat" text files, each of which holds a
+/// single conversation
+#[derive(Default)]
+pub struct WhatsAppSource {
+    // (conversation name, raw export text)
+    chats: Vec<(String, String)>,
+    date_order: Option<DateOrder>,
+}
+
+impl WhatsAppSource {
+    pub fn new() -> WhatsAppSource {
+        WhatsAppSource::default()
+    }
+
+    /// Opens either a bare `.txt` export, or a zip or directory of one with
+    /// its media
+    pub fn open(path: &str) -> Result<WhatsAppSource> {
+        let path = Path::new(path);
+        let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or("chat");
+
+        match path.extension().and_then(OsStr::to_str) {
+            Some("txt") => {
+                let mut text = String::new();
+                File::open(path)?.read_to_string(&mut text)?;
+
+                let mut source = WhatsAppSource::new();
+                source.add_chat(&chat_name(Some(stem), &text, stem), text);
+                Ok(source)
+            }
+            _ => {
+                let mut archive = Archive::open(path)?;
+                WhatsAppSource::from_archive(&mut archive, stem)
+            }
+        }
+    }
+
+    /// Reads every chat out of a zipped or unpacked export. iOS always names
+    /// the chat `_chat.txt`, so those are named after their participants, or
+    /// `archive_name` if nobody said anything
+    pub fn from_archive(archive: &mut Archive, archive_name: &str) -> Result<WhatsAppSource> {
+        let names = archive.file_names().to_vec();
+
+        let mut source = WhatsAppSource::new();
+        for (i, name) in names.iter().enumerate() {
+            let path = Path::new(name);
+            if path.extension().and_then(OsStr::to_str) != Some("txt") {
+                continue;
+            }
+
+            let text = archive.read_to_string(i)?;
+            let name = chat_name(
+                path.file_stem().and_then(OsStr::to_str),
+                &text,
+                archive_name,
+            );
+            source.add_chat(&name, text);
+        }
+
+        Ok(source)
+    }
+
+    pub fn add_chat(&mut self, name: &str, text: String) {
+        self.chats.push((String::from(name), text));
+    }
+
+    /// Overrides date order detection, for chats too short to guess from
+    pub fn with_date_order(mut self, date_order: DateOrder) -> WhatsAppSource {
+        self.date_order = Some(date_order);
+        self
+    }
+}
+
+impl ChatSource for WhatsAppSource {
+    fn conversation_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.chats.iter().map(|(name, _)| name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    fn read_conversation(&mut self, name: &str) -> Result<Option<Conversation>> {
+        let text = match self.chats.iter().find(|(chat_name, _)| chat_name == name) {
+            Some((_, text)) => text,
+            None => return Ok(None),
+        };
+        let (participants, mut messages) = parse_chat(text, self.date_order);
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        messages.sort_by_key(|a| a.timestamp);
+        println!(
+            "Parsed {} messages from WhatsApp chat {}",
+            messages.len(),
+            name
+        );
+
+        Ok(Some(Conversation {
+            name: String::from(name),
+            title: chat_title(name),
+            participants,
+            messages,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, Timelike};
+
+    #[test]
+    fn test_parse_chat_android() {
+        let text = "12/31/20, 11:59 PM - Messages and calls are end-to-end encrypted. \
+                    No one outside of this chat, not even WhatsApp, can read or listen to them.\n\
+                    12/31/20, 11:58 PM - Alice created group \"Plans: 2021\"\n\
+                    12/31/20, 11:59 PM - Alice: happy new year\n\
+                    1/1/21, 12:01 AM - Bob: <Media omitted>\n\
+                    1/1/21, 12:02 AM - Bob: first line\n\
+                    second line\n\
+                    1/1/21, 12:03 AM - Alice: I created this group for planning\n";
+        let (participants, messages) = parse_chat(text, None);
+
+        assert_eq!(participants.len(), 2);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].author, "Alice");
+        assert_eq!(messages[0].timestamp.hour(), 23);
+        assert_eq!(messages[1].kind, MessageKind::File);
+        assert_eq!(messages[1].render_content(), "FILES: omitted");
+        assert_eq!(messages[1].timestamp.hour(), 0);
+        assert_eq!(messages[2].content, "first line\nsecond line");
+        assert_eq!(messages[3].content, "I created this group for planning");
+    }
+
+    #[test]
+    fn test_parse_chat_ios() {
+        let text = "[31/12/2020, 23:59:59] Group: \u{200e}Messages and calls are end-to-end encrypted.\n\
+                    [31/12/2020, 23:59:59] Alice: hi: there\n\
+                    [31/12/2020, 23:59:59] Alice: \u{200e}Alice changed the subject to \u{201c}2021\u{201d}\n\
+                    [31/12/2020, 23:59:59] Bob: who changed the subject?\n\
+                    \u{200e}[01/01/2021, 00:00:05] Bob: \u{200e}<attached: 00000012-PHOTO-2021-01-01.jpg>\n";
+        let (participants, messages) = parse_chat(text, None);
+
+        assert_eq!(participants.len(), 2);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].content, "hi: there");
+        assert_eq!(messages[1].content, "who changed the subject?");
+        assert_eq!(messages[0].timestamp.day(), 31);
+        assert_eq!(messages[0].timestamp.month(), 12);
+        assert_eq!(messages[2].kind, MessageKind::Photo);
+        assert_eq!(
+            messages[2].render_content(),
+            "PHOTOS: -00000012-PHOTO-2021-01-01.jpg"
+        );
+    }
+
+    #[test]
+    fn test_ios_chat_name() {
+        // The same chat exported twice, once renamed by the OS
+        let root =
+            std::env::temp_dir().join(format!("chat_log_parser_whatsapp_{}", std::process::id()));
+        let text =
+            "[31/12/2020, 23:59:59] Bob: \u{200e}Messages and calls are end-to-end encrypted.\n\
+                    [31/12/2020, 23:59:59] Bob: hi\n\
+                    [01/01/2021, 00:00:05] Alice: hey\n";
+        let mut names = Vec::new();
+        for export in ["WhatsApp Chat - Bob", "WhatsApp Chat - Bob (1)"].iter() {
+            let dir = root.join(export);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("_chat.txt"), text).unwrap();
+            names.extend(
+                WhatsAppSource::open(dir.to_str().unwrap())
+                    .unwrap()
+                    .conversation_names(),
+            );
+        }
+        assert_eq!(
+            names,
+            vec!["WhatsApp Chat - Alice, Bob", "WhatsApp Chat - Alice, Bob"]
+        );
+        assert_eq!(chat_title(&names[0]), "Alice, Bob");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_parse_chat_date_order() {
+        let text = "31.12.20, 23:59 - Alice: a\n01.01.21, 00:00 - Bob: b\n";
+        let (_, messages) = parse_chat(text, None);
+        assert_eq!(messages[1].timestamp.month(), 1);
+        assert_eq!(messages[1].timestamp.year(), 2021);
+
+        let text = "01/02/21, 10:00 - Alice: a\n";
+        let (_, messages) = parse_chat(text, Some(DateOrder::DayMonthYear));
+        assert_eq!(messages[0].timestamp.month(), 2);
+    }
+}